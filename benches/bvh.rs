@@ -6,7 +6,7 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use image::{Rgb, RgbImage};
 
 
-criterion_group!(benches, tlas_intersection);
+criterion_group!(benches, tlas_intersection, render_buffer);
 criterion_main!(benches);
 
 fn tlas_intersection(criterion: &mut Criterion) {
@@ -26,43 +26,27 @@ fn tlas_intersection(criterion: &mut Criterion) {
             img_size
         );
 
-        let mut camera = BvhCamera::new(img_size, img_size);
-
-        // Bench: update camera with trans, since we dont get updated by a service here
-        camera.update(&GlobalTransform {
-            translation: vec3(0.0, 40.0, 100.0),
-            rotation: Quat::from_axis_angle(Vec3::X, -PI / 6.0),
-            ..Default::default()
-        });
-
+        let camera = bench_camera(img_size);
 
+        // single threaded and without render_tiles on purpose, this measures just the
+        // traversal so results stay comparable with earlier runs, render_buffer below
+        // covers the library path
         group.bench_function(name.clone(), |bencher| {
             bencher.iter(|| {
                 let mut img = RgbImage::new(camera.width, camera.height);
-                // TODO: this tiling doesnt work all resolutions, but its faster, so leaving it in for now            
-                let grid_edge_divisions: u32 = camera.width / 8;
-                for grid_x in 0..grid_edge_divisions {
-                    for grid_y in 0..grid_edge_divisions {
-                        for u in 0..(camera.width / grid_edge_divisions) {
-                            for v in 0..(camera.height / grid_edge_divisions) {
-                                // PERF: calculating an offset 2 loops up is slower than doing it in the inter loop
-                                let x = (grid_x * camera.width / grid_edge_divisions) + u;
-                                let y = (grid_y * camera.height / grid_edge_divisions) + v;
-                                let mut ray = camera.get_ray(
-                                    
-                                    x as f32 / camera.width as f32,
-                                    // TODO: image still reversed
-                                    y as f32 / camera.height as f32,
-                                );
-                                let color = if let Some(hit) = ray.intersect_tlas(&tlas) {
-                                    let c = vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v)) * 255.0;
-                                    Rgb([c.x as u8, c.y as u8, c.z as u8])
-                                } else {
-                                    Rgb([0, 0, 0])
-                                };
-                                img[(x, camera.height - 1 - y)] = color;
-                            }
-                        }
+                for tile in camera.tiles() {
+                    for (x, y) in tile.pixels() {
+                        let mut ray = camera.get_ray(
+                            (x as f32 + 0.5) / camera.width as f32,
+                            (y as f32 + 0.5) / camera.height as f32,
+                        );
+                        let color = if let Some(hit) = ray.intersect_tlas(&tlas) {
+                            let c = vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v)) * 255.0;
+                            Rgb([c.x as u8, c.y as u8, c.z as u8])
+                        } else {
+                            Rgb([0, 0, 0])
+                        };
+                        img[(x, y)] = color;
                     }
                 }
                 #[cfg(feature = "save")]
@@ -75,3 +59,49 @@ fn tlas_intersection(criterion: &mut Criterion) {
     group.finish();
 }
 
+// The library path users call, render_tiles on rayon with shading and shadow rays
+fn render_buffer(criterion: &mut Criterion) {
+    let tlas = build_random_tri_scene();
+    let settings = RenderSettings {
+        scene: RenderScene {
+            lights: vec![SceneLight::Directional {
+                direction: vec3(0.3, 1.0, 0.5).normalize(),
+                color: Vec3::ONE,
+            }],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut group = criterion.benchmark_group("render_to_buffer");
+    group.warm_up_time(Duration::from_millis(500));
+    for (img_size, time) in [(256, 10), (512, 30)] {
+        group.measurement_time(Duration::from_secs(time));
+        let name = format!("random_100k_tri_{}x{}", img_size, img_size);
+        let camera = bench_camera(img_size);
+        group.bench_function(name.clone(), |bencher| {
+            bencher.iter(|| {
+                let buffer = render_to_buffer(&tlas, &camera, &settings);
+                #[cfg(feature = "save")]
+                buffer.write_png(format!("target/{}_buffer.png", name)).unwrap();
+
+                black_box(buffer);
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_camera(img_size: u32) -> BvhCamera {
+    let mut camera = BvhCamera::new(img_size, img_size);
+    camera.tile_size = 8;
+
+    // Bench: update camera with trans, since we dont get updated by a service here
+    camera.update(&GlobalTransform {
+        translation: vec3(0.0, 40.0, 100.0),
+        rotation: Quat::from_axis_angle(Vec3::X, -PI / 6.0),
+        ..Default::default()
+    });
+    camera
+}
+
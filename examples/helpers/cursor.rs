@@ -1,4 +1,4 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, render::camera::Camera3d};
 use bevy_slyedoc_bvh::prelude::*;
 pub struct CursorPlugin;
 
@@ -38,29 +38,21 @@ fn load_cursor(
 }
 
 fn move_cursor(
-    windows: Res<Windows>,
-    camera_query: Query<(&GlobalTransform, &PerspectiveProjection)>,
+    raycast: BvhRaycast,
+    camera_query: Query<Entity, With<Camera3d>>,
     mut cusror_query: Query<(&mut Transform, &mut Visibility), With<Cursor>>,
-    tlas: Res<Tlas>,
 ) {
-    if let Some(window) = windows.get_primary() {
-        if let Some(mouse_pos) = window.cursor_position() {
-            if let Ok((trans, projection)) = camera_query.get_single() {
-                // get the cursor
-                let (mut cursor_trans, mut cursor_vis) = cusror_query.single_mut();
+    if let Ok(camera) = camera_query.get_single() {
+        // get the cursor
+        let (mut cursor_trans, mut cursor_vis) = cusror_query.single_mut();
 
-                // create a ray
-                let mut ray = Ray::from_screenspace(mouse_pos, window, projection, trans);
-
-                // test ray agaist tlas and see if we hit
-                if let Some(hit) = ray.intersect_tlas(&tlas) {
-                    // we could do something with the entity here
-                    cursor_trans.translation = ray.origin + ray.direction * hit.distance;
-                    cursor_vis.is_visible = true;
-                } else {
-                    cursor_vis.is_visible = false;
-                }
-            }
+        // cast a ray from the cursor and see if we hit
+        if let Some(hit) = raycast.from_cursor(camera, &QueryFilter::default()) {
+            // we could do something with the entity here
+            cursor_trans.translation = hit.position;
+            cursor_vis.is_visible = true;
+        } else {
+            cursor_vis.is_visible = false;
         }
     }
 }
@@ -5,7 +5,6 @@ use bevy::{
     prelude::{GlobalTransform},
 };
 use bevy_slyedoc_bvh::prelude::*;
-use image::{Rgb, RgbImage};
 
 
 #[cfg(feature = "trace")]
@@ -47,26 +46,11 @@ fn main() {
             ..Default::default()
         });
 
-        let mut img = RgbImage::new(camera.width, camera.height);
-        for y in 0..camera.height {
-            for x in 0..camera.width {
-                let mut ray = camera.get_ray(
-                    x as f32 / camera.width as f32,                    
-                    y as f32 / camera.height as f32,
-                );
-                let color = if let Some(hit) = ray.intersect_tlas(&tlas) {
-                    let c = vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v)) * 255.0;
-                    Rgb([c.x as u8, c.y as u8, c.z as u8])
-                } else {
-                    Rgb([0, 0, 0])
-                };
-
-                img[(x, camera.height - 1 - y)] = color;
-            }
-            
-        }
+        let buffer = render_to_buffer(&tlas, &camera, &RenderSettings::default());
         println!("Render time {}x{}: {:?}", camera.width, camera.height, render_time.elapsed());
 
-        img.save(format!("out/img_{}x{}.png", size, size)).unwrap();
+        buffer
+            .write_png(format!("out/img_{}x{}.png", size, size))
+            .unwrap();
     }
 }
@@ -16,6 +16,7 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .add_plugin(HelperPlugin) // See cusor plugin in helper plugins
         .add_plugin(BvhPlugin)
+        .add_plugin(BvhCameraPlugin)
         //.add_plugin(DebugLinesPlugin::default())
         .add_startup_system(helpers::setup_cameras)
         .add_startup_system(helpers::load_enviroment)
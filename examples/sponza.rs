@@ -13,6 +13,7 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .add_plugin(HelperPlugin) // See cusor plugin in helper plugins
         .add_plugin(BvhPlugin)
+        .add_plugin(BvhCameraPlugin)
         .add_startup_system(helpers::setup_cameras)
         .add_startup_system(helpers::load_enviroment)
         .add_startup_system(helpers::load_sponza) // Check this function out 
@@ -1,6 +1,9 @@
-use bevy::{prelude::*, };
+use bevy::{math::vec3, prelude::*};
+use bytemuck::{Pod, Zeroable};
 
-#[derive(Debug, Copy, Clone)]
+// repr(C) and Pod so bvh files can be copied straight in, see loader.rs
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
 pub struct Aabb {
     pub bmin: Vec3,
     pub bmax: Vec3,
@@ -30,4 +33,31 @@ impl Aabb {
         let e = self.bmax - self.bmin; // box extent
         e.x * e.y + e.y * e.z + e.z * e.x
     }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        p.cmpge(self.bmin).all() && p.cmple(self.bmax).all()
+    }
+
+    // zero when the point is inside
+    pub fn distance_squared(&self, p: Vec3) -> f32 {
+        let d = (self.bmin - p).max(p - self.bmax).max(Vec3::ZERO);
+        d.length_squared()
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.bmin.cmple(other.bmax).all() && other.bmin.cmple(self.bmax).all()
+    }
+
+    // bounds of the box after transforming all 8 corners
+    pub fn transformed(&self, trans: &Mat4) -> Aabb {
+        let mut result = Aabb::default();
+        for i in 0..8 {
+            result.grow(trans.transform_point3(vec3(
+                if i & 1 != 0 { self.bmax.x } else { self.bmin.x },
+                if i & 2 != 0 { self.bmax.y } else { self.bmin.y },
+                if i & 4 != 0 { self.bmax.z } else { self.bmin.z },
+            )));
+        }
+        result
+    }
 }
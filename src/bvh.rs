@@ -1,7 +1,9 @@
-use crate::{aabb::Aabb, tri::Tri, BIN_COUNT};
-use bevy::{math::vec3, prelude::*, reflect::TypeUuid};
+use crate::{aabb::Aabb, filter::BvhLayers, ray::Ray, tri::{Tri, TriData}, BIN_COUNT};
+use bevy::{math::const_vec3, prelude::*, reflect::TypeUuid};
+use bytemuck::{Pod, Zeroable};
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
 pub struct BvhNode {
     pub aabb: Aabb,
     pub left_first: u32,
@@ -20,12 +22,18 @@ impl BvhNode {
     }
 }
 
+/// Overlapping triangles between two bvhs, first index is into the first bvh's tris
+pub type TriPair = (usize, usize);
+
 #[derive(Debug)]
 pub struct BvhInstance {
     pub entity: Entity,
     pub bvh_index: usize,
     pub inv_trans: Mat4,
     pub bounds: Aabb,
+    pub layers: u32,
+    // used by the cpu renderer, none renders with the default material
+    pub material: Option<Handle<StandardMaterial>>,
 }
 
 impl BvhInstance {
@@ -35,6 +43,8 @@ impl BvhInstance {
             bvh_index,
             inv_trans: Mat4::default(),
             bounds: Aabb::default(),
+            layers: BvhLayers::ALL.0,
+            material: None,
         }
     }
 
@@ -43,33 +53,64 @@ impl BvhInstance {
         let trans_matrix = trans.compute_matrix();
         self.inv_trans = trans_matrix.inverse();
 
-        // calculate world-space bounds using the new matrix
-        let bmin = root.aabb.bmin;
-        let bmax = root.aabb.bmax;
-        for i in 0..8 {
-            self.bounds.grow(trans_matrix.transform_point3(vec3(
-                if i & 1 != 0 { bmax.x } else { bmin.x },
-                if i & 2 != 0 { bmax.y } else { bmin.y },
-                if i & 4 != 0 { bmax.z } else { bmin.z },
-            )));
-        }
+        // calculate world-space bounds using the new matrix, starting fresh so
+        // the bounds don't keep growing as the entity moves
+        self.bounds = root.aabb.transformed(&trans_matrix);
+    }
+
+    /// Closest point on the instance surface in world space, and its triangle index
+    ///
+    /// Search is done in mesh space, so its only exact for rigid and uniformly scaled instances
+    pub fn closest_point(&self, point: Vec3, bvhs: &[Bvh]) -> Option<(Vec3, usize)> {
+        let local_point = self.inv_trans.transform_point3(point);
+        bvhs[self.bvh_index]
+            .closest_point(local_point)
+            .map(|(closest, tri_index)| {
+                (
+                    self.inv_trans.inverse().transform_point3(closest),
+                    tri_index,
+                )
+            })
+    }
+
+    pub fn intersects(&self, other: &BvhInstance, bvhs: &[Bvh]) -> bool {
+        bvhs[self.bvh_index].intersects(
+            &self.inv_trans.inverse(),
+            &bvhs[other.bvh_index],
+            &other.inv_trans.inverse(),
+        )
+    }
+
+    pub fn overlapping_tris(&self, other: &BvhInstance, bvhs: &[Bvh]) -> Vec<TriPair> {
+        bvhs[self.bvh_index].overlapping_tris(
+            &self.inv_trans.inverse(),
+            &bvhs[other.bvh_index],
+            &other.inv_trans.inverse(),
+        )
     }
 }
 
-#[derive(Default, Component, Debug, TypeUuid)]
+#[derive(Default, Component, Debug, Clone, TypeUuid)]
 #[uuid = "81299f9d-41e0-4ff0-86b7-6bef6c3f67c1"]
 pub struct Bvh {
     pub nodes: Vec<BvhNode>,
     pub tris: Vec<Tri>,
+    // same order as tris, empty if the mesh had no normals
+    pub tri_data: Vec<TriData>,
     pub triangle_indexs: Vec<usize>,
 }
 
 impl Bvh {
     // TODO: need far better way to get tris from bevy mesh
     pub fn new(triangles: Vec<Tri>) -> Bvh {
+        Self::new_with_data(triangles, Vec::new())
+    }
+
+    pub fn new_with_data(triangles: Vec<Tri>, tri_data: Vec<TriData>) -> Bvh {
         let count = triangles.len() as u32;
         let mut bvh = Bvh {
             tris: triangles,
+            tri_data,
             nodes: {
                 // Add root node and empty node to offset 1
                 let mut nodes = Vec::with_capacity(64);
@@ -88,11 +129,194 @@ impl Bvh {
             triangle_indexs: (0..count as usize).collect::<Vec<_>>(),
         };
 
-        bvh.update_node_bounds(0);
-        bvh.subdivide_node(0);
+        // an empty mesh keeps the empty root, traversal skips bvhs without triangles
+        if count > 0 {
+            bvh.update_node_bounds(0);
+            bvh.subdivide_node(0);
+        }
         bvh
     }
 
+    /// Tests if a point in mesh space is inside the mesh, only meaningful for closed meshes
+    ///
+    /// Uses ray parity along a few skewed directions and takes the majority vote, so a ray
+    /// grazing an edge or running along a face doesn't flip the answer. Points on the surface
+    /// count as inside, same as Aabb::contains
+    pub fn contains_point(&self, point: Vec3) -> bool {
+        // with some slack so points on the surface that went through a transform aren't missed
+        if self.tris.is_empty()
+            || self.nodes[0].aabb.distance_squared(point) >= SURFACE_EPSILON * SURFACE_EPSILON
+        {
+            return false;
+        }
+        // parity can't tell which side a point on a face is, the rays start on it
+        if let Some((closest, _)) = self.closest_point(point) {
+            if closest.distance_squared(point) < SURFACE_EPSILON * SURFACE_EPSILON {
+                return true;
+            }
+        }
+
+        let mut hits = Vec::new();
+        let inside_votes = POINT_TEST_DIRECTIONS
+            .iter()
+            .filter(|direction| {
+                hits.clear();
+                Ray::new(point, direction.normalize()).intersect_bvh_all(
+                    self,
+                    Entity::from_raw(0),
+                    &mut hits,
+                );
+
+                // a ray through a shared edge or vertex reports the same crossing more than once
+                hits.sort_unstable_by(|a, b| a.distance.total_cmp(&b.distance));
+                hits.dedup_by(|a, b| (a.distance - b.distance).abs() < SURFACE_EPSILON);
+                hits.len() % 2 == 1
+            })
+            .count();
+        inside_votes * 2 > POINT_TEST_DIRECTIONS.len()
+    }
+
+    /// Closest point on the mesh surface and its triangle index, in mesh space
+    pub fn closest_point(&self, point: Vec3) -> Option<(Vec3, usize)> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("closest_point").entered();
+        if self.tris.is_empty() {
+            return None;
+        }
+
+        let mut best_distance = f32::MAX;
+        let mut best = None;
+        let mut stack = Vec::with_capacity(64);
+        stack.push(&self.nodes[0]);
+        while let Some(node) = stack.pop() {
+            if node.aabb.distance_squared(point) >= best_distance {
+                continue;
+            }
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    let tri_index = self.triangle_indexs[(node.left_first + i) as usize];
+                    let closest = self.tris[tri_index].closest_point(point);
+                    let distance = closest.distance_squared(point);
+                    if distance < best_distance {
+                        best_distance = distance;
+                        best = Some((closest, tri_index));
+                    }
+                }
+                continue;
+            }
+            // push the far child first so the near one is tested first
+            let mut child1 = &self.nodes[node.left_first as usize];
+            let mut child2 = &self.nodes[(node.left_first + 1) as usize];
+            if child1.aabb.distance_squared(point) < child2.aabb.distance_squared(point) {
+                std::mem::swap(&mut child1, &mut child2);
+            }
+            stack.push(child1);
+            stack.push(child2);
+        }
+        best
+    }
+
+    /// Tests if this bvh overlaps another, each with its own world transform
+    ///
+    /// Transforms can be anything, so this works for placements that aren't in the tlas yet.
+    /// A mesh fully inside the other counts too, so both should be closed for that part
+    pub fn intersects(&self, trans: &Mat4, other: &Bvh, other_trans: &Mat4) -> bool {
+        if !self
+            .find_overlaps(trans, other, other_trans, true)
+            .is_empty()
+        {
+            return true;
+        }
+        if self.tris.is_empty() || other.tris.is_empty() {
+            return false;
+        }
+
+        // no surfaces cross, so either one is inside the other or they're apart,
+        // and any vertex tells us which
+        let to_local = trans.inverse() * *other_trans;
+        self.contains_point(to_local.transform_point3(other.tris[0].vertex0))
+            || other.contains_point(to_local.inverse().transform_point3(self.tris[0].vertex0))
+    }
+
+    /// Finds every pair of overlapping triangles between this bvh and another
+    ///
+    /// Only surfaces that cross are found, see intersects for containment
+    pub fn overlapping_tris(&self, trans: &Mat4, other: &Bvh, other_trans: &Mat4) -> Vec<TriPair> {
+        self.find_overlaps(trans, other, other_trans, false)
+    }
+
+    // Descends both trees at once, working in this bvh's space
+    fn find_overlaps(
+        &self,
+        trans: &Mat4,
+        other: &Bvh,
+        other_trans: &Mat4,
+        first_only: bool,
+    ) -> Vec<TriPair> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("find_overlaps").entered();
+        let mut pairs = Vec::new();
+        if self.tris.is_empty() || other.tris.is_empty() {
+            return pairs;
+        }
+
+        // takes the other bvh into our space
+        let to_local = trans.inverse() * *other_trans;
+        let mut b_tris = Vec::new();
+        let mut stack = Vec::with_capacity(64);
+        stack.push((0usize, 0usize));
+        while let Some((a_index, b_index)) = stack.pop() {
+            let a = &self.nodes[a_index];
+            let b = &other.nodes[b_index];
+            let b_aabb = b.aabb.transformed(&to_local);
+            if !a.aabb.intersects(&b_aabb) {
+                continue;
+            }
+
+            match (a.is_leaf(), b.is_leaf()) {
+                (true, true) => {
+                    // transform the other leaf's triangles once for the pair
+                    b_tris.clear();
+                    b_tris.extend((0..b.tri_count).map(|j| {
+                        let b_tri_index = other.triangle_indexs[(b.left_first + j) as usize];
+                        (b_tri_index, other.tris[b_tri_index].transformed(&to_local))
+                    }));
+                    for i in 0..a.tri_count {
+                        let a_tri_index = self.triangle_indexs[(a.left_first + i) as usize];
+                        let a_tri = &self.tris[a_tri_index];
+                        for (b_tri_index, b_tri) in &b_tris {
+                            if a_tri.intersects_tri(b_tri) {
+                                pairs.push((a_tri_index, *b_tri_index));
+                                if first_only {
+                                    return pairs;
+                                }
+                            }
+                        }
+                    }
+                }
+                (false, true) => {
+                    stack.push((a.left_first as usize, b_index));
+                    stack.push((a.left_first as usize + 1, b_index));
+                }
+                (true, false) => {
+                    stack.push((a_index, b.left_first as usize));
+                    stack.push((a_index, b.left_first as usize + 1));
+                }
+                // split the larger node
+                (false, false) => {
+                    if a.aabb.area() >= b_aabb.area() {
+                        stack.push((a.left_first as usize, b_index));
+                        stack.push((a.left_first as usize + 1, b_index));
+                    } else {
+                        stack.push((a_index, b.left_first as usize));
+                        stack.push((a_index, b.left_first as usize + 1));
+                    }
+                }
+            }
+        }
+        pairs
+    }
+
     // pub fn refit(&mut self, triangles: &[Tri]) {
     //     for i in (0..(self.open_node - 1)).rev() {
     //         if i != 1 {
@@ -245,8 +469,105 @@ impl Bvh {
     }
 }
 
+// Directions for inside tests, kept off axis so they dont run along axis aligned faces
+// distance in mesh space below which contains_point treats a point as on the surface
+const SURFACE_EPSILON: f32 = 0.0001;
+
+const POINT_TEST_DIRECTIONS: [Vec3; 3] = [
+    const_vec3!([0.57, 0.58, 0.59]),
+    const_vec3!([-0.27, 0.8, -0.53]),
+    const_vec3!([0.7, -0.32, -0.63]),
+];
+
 #[derive(Default, Debug, Copy, Clone)]
 struct Bin {
     bounds: Aabb,
     tri_count: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_mesh;
+
+    fn cube(size: f32) -> Bvh {
+        let (tris, tri_data) = parse_mesh(&Mesh::from(shape::Cube { size }));
+        Bvh::new_with_data(tris, tri_data)
+    }
+
+    #[test]
+    fn contained_meshes_intersect() {
+        let wall = cube(4.0);
+        let prop = cube(1.0);
+        let inside = Mat4::from_translation(Vec3::new(0.5, 0.0, 0.0));
+        assert!(wall
+            .overlapping_tris(&Mat4::IDENTITY, &prop, &inside)
+            .is_empty());
+        assert!(wall.intersects(&Mat4::IDENTITY, &prop, &inside));
+        assert!(prop.intersects(&inside, &wall, &Mat4::IDENTITY));
+
+        let crossing = Mat4::from_translation(Vec3::new(2.0, 0.0, 0.0));
+        assert!(wall.intersects(&Mat4::IDENTITY, &prop, &crossing));
+
+        let apart = Mat4::from_translation(Vec3::new(5.0, 0.0, 0.0));
+        assert!(!wall.intersects(&Mat4::IDENTITY, &prop, &apart));
+    }
+
+    #[test]
+    fn cube_contains_point() {
+        let cube = cube(2.0);
+        assert!(cube.contains_point(Vec3::ZERO));
+        assert!(cube.contains_point(Vec3::new(0.999, 0.2, -0.3)));
+        assert!(!cube.contains_point(Vec3::new(1.001, 0.2, -0.3)));
+        assert!(cube.contains_point(Vec3::new(0.3, -0.2, -0.999)));
+        assert!(!cube.contains_point(Vec3::new(0.3, -0.2, -1.001)));
+        assert!(cube.contains_point(Vec3::new(0.999, 0.999, 0.999)));
+        assert!(!cube.contains_point(Vec3::new(1.001, 1.001, 1.001)));
+
+        // on a face, an edge and a vertex
+        assert!(cube.contains_point(Vec3::new(1.0, 0.2, -0.3)));
+        assert!(cube.contains_point(Vec3::new(1.0, 1.0, 0.4)));
+        assert!(cube.contains_point(Vec3::new(-1.0, 1.0, -1.0)));
+        assert!(!cube.contains_point(Vec3::new(1.0, 1.0, 1.001)));
+    }
+
+    #[test]
+    fn grazing_ray_doesnt_flip_contains_point() {
+        let cube = cube(2.0);
+        for direction in POINT_TEST_DIRECTIONS {
+            let direction = direction.normalize();
+            // outside, with one test ray just touching the edge where two faces meet
+            let edge = Vec3::new(direction.x.signum(), -direction.y.signum(), 0.0);
+            let point = edge - direction * 0.5;
+            if point.x.abs() <= 1.0 && point.y.abs() <= 1.0 {
+                continue;
+            }
+            assert!(!cube.contains_point(point), "{:?}", point);
+
+            // inside, leaving through a corner
+            let corner = direction.signum();
+            assert!(cube.contains_point(corner - direction * 0.5));
+        }
+    }
+
+    #[test]
+    fn sphere_contains_point() {
+        let (tris, tri_data) = parse_mesh(&Mesh::from(shape::UVSphere {
+            radius: 1.0,
+            sectors: 16,
+            stacks: 8,
+        }));
+        let sphere = Bvh::new_with_data(tris, tri_data);
+        assert!(sphere.contains_point(Vec3::ZERO));
+        assert!(sphere.contains_point(Vec3::new(0.0, 0.0, 0.99)));
+        assert!(!sphere.contains_point(Vec3::new(0.0, 0.0, 1.01)));
+        assert!(!sphere.contains_point(Vec3::new(0.0, 1.01, 0.0)));
+
+        // every vertex is on the surface, poles and seam included
+        for tri in &sphere.tris {
+            assert!(sphere.contains_point(tri.vertex0));
+            assert!(!sphere.contains_point(tri.vertex0 * 1.01));
+            assert!(sphere.contains_point(tri.vertex0 * 0.95));
+        }
+    }
+}
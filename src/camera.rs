@@ -1,81 +1,411 @@
-use crate::ray::Ray;
-use bevy::prelude::*;
+use crate::{
+    ray::Ray,
+    render::{PixelFilter, RenderMode, RenderScene},
+    tlas::Tlas,
+};
+use bevy::{prelude::*, render::camera::CameraProjection};
 
-// TODO: Make this projection based
+/// Cpu rendered view, rays come from the entity's projection so the image lines up with bevy's
+///
+/// With a bevy Camera on the entity the projection is sized to its target, so the image always
+/// shows the same view, and matches pixel for pixel when it's the target's size (see follow_window)
 #[derive(Component)]
 pub struct BvhCamera {
     pub width: u32,
     pub height: u32,
     pub origin: Vec3,
-    viewport_height: f32,
-    viewport_width: f32,
-    lower_left_corner: Vec3,
-    focus_dist: f32,
-    horizontal: Vec3,
-    vertical: Vec3,
-    u: Vec3,
-    v: Vec3,
-    w: Vec3,
+    forward: Vec3,
+    right: Vec3,
+    up: Vec3,
+    projection_matrix: Mat4,
+    ndc_to_world: Mat4,
+    // lens radius in world units, 0 is a pinhole with everything in focus
+    pub aperture: f32,
+    // distance along the view direction that stays sharp
+    pub focus_dist: f32,
+    // aperture and focus_dist the accumulation was started with
+    accumulated_lens: (f32, f32),
     pub samples: u32,
+    // how samples are jittered and averaged when there is more than one per pixel
+    pub filter: PixelFilter,
+    pub mode: RenderMode,
+    // trace shadow rays to every light when shading
+    pub shadows: bool,
+    // ambient occlusion rays per hit, 0 disables it
+    pub ao_samples: u32,
+    pub ao_radius: f32,
+    // path trace bounces after the camera ray
+    pub max_bounces: u32,
+    // nodes visited plus triangles tested that shows as red in heatmap mode
+    pub heatmap_max: u32,
+    // hdr sum of the path traced frames since the last reset
+    accumulation: Vec<Vec3>,
+    accumulated_frames: u32,
+    // width and height of the square tiles rendered in parallel, edge tiles get cut short
+    pub tile_size: u32,
+    // fill the aov buffer each frame
+    pub aovs: bool,
+    aov_buffer: Vec<AovPixel>,
+    // rgba8 target image sized to the camera, created for you when left empty
     pub image: Option<Handle<Image>>,
+    // ui position to show the image at, None to not show it
+    pub display: Option<Rect<Val>>,
+    pub trigger: RenderTrigger,
+    // scale of the primary window's physical size to render at, replaces width and height
+    pub follow_window: Option<f32>,
+    frames_since_render: u32,
+    render_requested: bool,
+}
+
+/// When a BvhCamera renders
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RenderTrigger {
+    #[default]
+    EveryFrame,
+    // every nth frame, 1 is the same as every frame
+    EveryNFrames(u32),
+    // only after request_render, the first frame always renders
+    OnDemand,
 }
 
 impl BvhCamera {
+    /// Width and height can be changed later, the image is resized to match
     pub fn new(width: u32, height: u32) -> Self {
-        // TODO: after messing the params I am defualting more
-        let vfov: f32 = 45.0; // vertical field of view
-        let focus_dist: f32 = 1.0; // TODO: not using this yet
-        let samples: u32 = 1;
-
-        let aspect_ratio = width as f32 / height as f32;
-        let theta = vfov * std::f32::consts::PI / 180.0;
-        let half_height = (theta / 2.0).tan();
-        let viewport_height = 2.0 * half_height;
-        let viewport_width = aspect_ratio * viewport_height;
-
-        Self {
+        let mut camera = Self {
             width,
             height,
-            viewport_height,
-            viewport_width,
-            focus_dist,
-            samples,
+            samples: 1,
+            filter: PixelFilter::default(),
             // Rest will be updated every frame for now
             origin: Vec3::ZERO,
-            lower_left_corner: Vec3::ZERO,
-            horizontal: Vec3::ZERO,
-            vertical: Vec3::ZERO,
-            u: Vec3::ZERO,
-            v: Vec3::ZERO,
-            w: Vec3::ONE,
+            forward: -Vec3::Z,
+            right: Vec3::X,
+            up: Vec3::Y,
+            aperture: 0.0,
+            focus_dist: 10.0,
+            accumulated_lens: (0.0, 10.0),
+            projection_matrix: Mat4::IDENTITY,
+            ndc_to_world: Mat4::IDENTITY,
+            mode: RenderMode::default(),
+            shadows: true,
+            ao_samples: 0,
+            ao_radius: 1.0,
+            max_bounces: 4,
+            heatmap_max: 200,
+            accumulation: Vec::new(),
+            accumulated_frames: 0,
+            tile_size: 16,
+            aovs: false,
+            aov_buffer: Vec::new(),
             image: None,
-        }
+            display: Some(Rect {
+                bottom: Val::Px(50.0),
+                right: Val::Px(10.0),
+                ..default()
+            }),
+            trigger: RenderTrigger::default(),
+            follow_window: None,
+            frames_since_render: 0,
+            render_requested: true,
+        };
+        // bevy's default 45 degree fov, used when the entity has no projection
+        camera.set_projection(&PerspectiveProjection::default());
+        camera
+    }
+
+    /// Uses the projection sized to this camera's image
+    pub fn set_projection(&mut self, projection: &(impl CameraProjection + Clone)) {
+        self.set_projection_sized(projection, Vec2::new(self.width as f32, self.height as f32));
+    }
+
+    /// Uses the projection sized to the view it's shown in, like bevy sizes it to the window
+    ///
+    /// The image then covers that whole view at its own resolution, so an orthographic
+    /// projection using ScalingMode::WindowSize still spans the window's width
+    pub fn set_projection_sized(
+        &mut self,
+        projection: &(impl CameraProjection + Clone),
+        view_size: Vec2,
+    ) {
+        let mut projection = projection.clone();
+        projection.update(view_size.x, view_size.y);
+        self.projection_matrix = projection.get_projection_matrix();
     }
 
     pub fn update(&mut self, trans: &GlobalTransform) {
+        let ndc_to_world = trans.compute_matrix() * self.projection_matrix.inverse();
+        let lens = (self.aperture, self.focus_dist);
+        if ndc_to_world != self.ndc_to_world || lens != self.accumulated_lens {
+            self.reset_accumulation();
+        }
+        self.ndc_to_world = ndc_to_world;
+        self.accumulated_lens = lens;
         self.origin = trans.translation;
+        self.forward = trans.forward();
+        self.right = trans.right();
+        self.up = trans.up();
+    }
 
-        self.w = -trans.forward();
-        self.u = trans.right();
-        self.v = trans.up();
+    /// Ray through the image, u and v go from 0 to 1 with (0, 0) being the top left corner
+    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
+        let ndc = Vec2::new(u * 2.0 - 1.0, 1.0 - v * 2.0);
+        Ray::from_ndc_to_world(ndc, &self.ndc_to_world)
+    }
 
-        self.horizontal = self.focus_dist * self.viewport_width * self.u;
-        self.vertical = self.focus_dist * self.viewport_height * self.v;
+    /// Thin lens version of get_ray, lens is a point in the unit disk scaled by the aperture
+    ///
+    /// Every ray through the same pixel meets on the focus plane, so only things there stay sharp
+    pub fn get_lens_ray(&self, u: f32, v: f32, lens: Vec2) -> Ray {
+        let ray = self.get_ray(u, v);
+        if self.aperture <= 0.0 {
+            return ray;
+        }
+        let focus_plane = self.origin + self.forward * self.focus_dist;
+        let t = (focus_plane - ray.origin).dot(self.forward) / ray.direction.dot(self.forward);
+        let focus = ray.origin + ray.direction * t;
+        let origin = ray.origin + (self.right * lens.x + self.up * lens.y) * self.aperture;
+        Ray::new(origin, (focus - origin).normalize())
+    }
 
-        self.lower_left_corner =
-            self.origin - self.horizontal / 2.0 - self.vertical / 2.0 - self.focus_dist * self.w;
+    /// Renders next frame, for RenderTrigger::OnDemand
+    pub fn request_render(&mut self) {
+        self.render_requested = true;
     }
 
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let direction = (self.lower_left_corner + u * self.horizontal + v * self.vertical
-            - self.origin)
-            .normalize();
-        Ray {
-            origin: self.origin,
-            direction,
-            direction_inv: direction.recip(),
-            distance: 1e30f32,
-            hit: None,
+    // called once a frame, true if the camera should render this frame
+    pub(crate) fn should_render(&mut self) -> bool {
+        let render = match self.trigger {
+            RenderTrigger::EveryFrame => true,
+            RenderTrigger::EveryNFrames(n) => {
+                self.render_requested || self.frames_since_render + 1 >= n.max(1)
+            }
+            RenderTrigger::OnDemand => self.render_requested,
+        };
+        if render {
+            self.frames_since_render = 0;
+            self.render_requested = false;
+        } else {
+            self.frames_since_render += 1;
         }
+        render
+    }
+
+    pub fn tiles(&self) -> Tiles {
+        Tiles::new(self.width, self.height, self.tile_size)
+    }
+
+    /// Starts the path traced image over, done for you when the camera or scene changes
+    pub fn reset_accumulation(&mut self) {
+        self.accumulation.clear();
+        self.accumulated_frames = 0;
+    }
+
+    pub fn accumulated_frames(&self) -> u32 {
+        self.accumulated_frames
+    }
+
+    /// Averaged linear radiance per pixel from the path tracer, rows from the top
+    pub fn hdr_buffer(&self) -> Vec<Vec3> {
+        let scale = 1.0 / self.accumulated_frames.max(1) as f32;
+        self.accumulation.iter().map(|c| *c * scale).collect()
+    }
+
+    // hands the buffer to the renderer, sized and cleared if it was reset
+    pub(crate) fn take_accumulation(&mut self) -> Vec<Vec3> {
+        let len = (self.width * self.height) as usize;
+        if self.accumulation.len() != len {
+            self.accumulation = vec![Vec3::ZERO; len];
+            self.accumulated_frames = 0;
+        }
+        std::mem::take(&mut self.accumulation)
+    }
+
+    pub(crate) fn finish_accumulation(&mut self, accumulation: Vec<Vec3>, added_frame: bool) {
+        self.accumulation = accumulation;
+        if added_frame {
+            self.accumulated_frames += 1;
+        }
+    }
+
+    /// Per pixel aovs from the last frame, rows from the top, empty unless aovs is set
+    pub fn aov_buffer(&self) -> &[AovPixel] {
+        &self.aov_buffer
+    }
+
+    pub(crate) fn take_aovs(&mut self) -> Vec<AovPixel> {
+        let len = if self.aovs {
+            (self.width * self.height) as usize
+        } else {
+            0
+        };
+        self.aov_buffer.resize(len, AovPixel::MISS);
+        std::mem::take(&mut self.aov_buffer)
+    }
+
+    pub(crate) fn finish_aovs(&mut self, aovs: Vec<AovPixel>) {
+        self.aov_buffer = aovs;
+    }
+
+    // camera ray through (u, v), same coordinates as get_ray
+    pub(crate) fn trace_aov(&self, u: f32, v: f32, scene: &RenderScene, tlas: &Tlas) -> AovPixel {
+        let mut ray = self.get_ray(u, v);
+        match scene.intersect(&mut ray, tlas) {
+            Some(hit) => AovPixel {
+                depth: (ray.origin + ray.direction * hit.distance - self.origin).dot(self.forward),
+                normal: tlas.hit_normal(&hit, -ray.direction),
+                barycentric: Vec3::new(1.0 - (hit.u + hit.v), hit.u, hit.v),
+                entity: Some(hit.entity),
+                tri_index: hit.tri_index,
+            },
+            None => AovPixel::MISS,
+        }
+    }
+}
+
+/// Extra per pixel outputs, from a single camera ray through the pixel center
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AovPixel {
+    // distance along the camera's forward axis, infinite on a miss
+    pub depth: f32,
+    // world space, facing the camera
+    pub normal: Vec3,
+    // weights of the triangle's vertex0, vertex1 and vertex2
+    pub barycentric: Vec3,
+    pub entity: Option<Entity>,
+    // index into the entity's bvh tris, only meaningful with an entity
+    pub tri_index: usize,
+}
+
+impl AovPixel {
+    pub const MISS: AovPixel = AovPixel {
+        depth: f32::INFINITY,
+        normal: Vec3::ZERO,
+        barycentric: Vec3::ZERO,
+        entity: None,
+        tri_index: 0,
+    };
+}
+
+/// Square region of an image, cut short at the right and bottom edges
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    pub fn len(&self) -> usize {
+        (self.width * self.height) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Image coordinates in the tile, row by row
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let tile = *self;
+        (tile.y..tile.y + tile.height)
+            .flat_map(move |y| (tile.x..tile.x + tile.width).map(move |x| (x, y)))
+    }
+}
+
+/// Covers a width by height image in tiles, left to right then top to bottom
+#[derive(Debug, Clone)]
+pub struct Tiles {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    x: u32,
+    y: u32,
+}
+
+impl Tiles {
+    pub fn new(width: u32, height: u32, tile_size: u32) -> Self {
+        Self {
+            width,
+            height,
+            tile_size: tile_size.max(1),
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+impl Iterator for Tiles {
+    type Item = Tile;
+
+    fn next(&mut self) -> Option<Tile> {
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += self.tile_size;
+        }
+        if self.y >= self.height || self.width == 0 {
+            return None;
+        }
+        let tile = Tile {
+            x: self.x,
+            y: self.y,
+            width: self.tile_size.min(self.width - self.x),
+            height: self.tile_size.min(self.height - self.y),
+        };
+        self.x += self.tile_size;
+        Some(tile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        let (width, height) = (333, 171);
+        for tile_size in [0, 1, 7, 16, 64, 171, 333, 1000] {
+            let mut seen = vec![0u32; (width * height) as usize];
+            for tile in Tiles::new(width, height, tile_size) {
+                assert!(!tile.is_empty());
+                for (x, y) in tile.pixels() {
+                    assert!(x < width && y < height);
+                    seen[(y * width + x) as usize] += 1;
+                }
+            }
+            assert!(
+                seen.iter().all(|count| *count == 1),
+                "tile size {}",
+                tile_size
+            );
+        }
+    }
+
+    #[test]
+    fn tiles_of_empty_image() {
+        for (width, height) in [(0, 0), (0, 171), (333, 0)] {
+            assert_eq!(Tiles::new(width, height, 16).count(), 0);
+        }
+    }
+
+    #[test]
+    fn projection_sized_to_view() {
+        // a small camera on a 1280x720 window still sees the window's width in world units
+        let mut camera = BvhCamera::new(512, 512);
+        camera.set_projection_sized(&OrthographicProjection::default(), Vec2::new(1280.0, 720.0));
+        camera.update(&GlobalTransform::from_xyz(0.0, 0.0, 10.0));
+        let top_left = camera.get_ray(0.0, 0.0);
+        assert!(top_left
+            .origin
+            .truncate()
+            .abs_diff_eq(Vec2::new(-640.0, 360.0), 1e-3));
+
+        // sized to the image otherwise
+        camera.set_projection(&OrthographicProjection::default());
+        camera.update(&GlobalTransform::from_xyz(0.0, 0.0, 10.0));
+        let top_left = camera.get_ray(0.0, 0.0);
+        assert!(top_left
+            .origin
+            .truncate()
+            .abs_diff_eq(Vec2::new(-256.0, 256.0), 1e-3));
     }
 }
@@ -0,0 +1,258 @@
+use crate::{aabb::Aabb, bvh::Bvh, render::heat_color, tlas::Tlas, BvhSystems};
+use bevy::{
+    prelude::*,
+    render::{mesh::PrimitiveTopology, view::NoFrustumCulling},
+};
+
+// node colors, each drawn as its own line mesh since StandardMaterial has no vertex colors
+const BUCKETS: usize = 8;
+
+/// Draws tlas and bvh node bounds as wireframe boxes, see BvhDebug for what gets drawn
+pub struct BvhDebugPlugin;
+
+impl Plugin for BvhDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BvhDebug>()
+            .add_startup_system(setup_debug)
+            .add_system_to_stage(CoreStage::PostUpdate, update_debug.after(BvhSystems::Setup));
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugColor {
+    /// Cycles through colors by depth
+    #[default]
+    Level,
+    /// Blue to red by surface area times triangles under the node, relative to the drawn nodes
+    SahCost,
+}
+
+pub struct BvhDebug {
+    // draw the tlas nodes, in world space
+    pub tlas: bool,
+    // draw this entity's bvh nodes, following its transform
+    pub entity: Option<Entity>,
+    // node depths to draw, the root is 0
+    pub min_depth: u32,
+    pub max_depth: u32,
+    pub color: DebugColor,
+}
+
+impl Default for BvhDebug {
+    fn default() -> Self {
+        Self {
+            tlas: true,
+            entity: None,
+            min_depth: 0,
+            max_depth: u32::MAX,
+            color: DebugColor::default(),
+        }
+    }
+}
+
+#[derive(Component)]
+struct DebugLines {
+    blas: bool,
+    bucket: usize,
+}
+
+struct DebugNode {
+    aabb: Aabb,
+    depth: u32,
+    tris: usize,
+}
+
+fn setup_debug(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for blas in [false, true] {
+        for bucket in 0..BUCKETS {
+            commands
+                .spawn_bundle(PbrBundle {
+                    mesh: meshes.add(line_mesh(Vec::new())),
+                    material: materials.add(StandardMaterial {
+                        base_color: Color::WHITE,
+                        unlit: true,
+                        ..default()
+                    }),
+                    visibility: Visibility { is_visible: false },
+                    ..default()
+                })
+                // bounds are only calculated when the mesh is added, and the lines change
+                .insert(NoFrustumCulling)
+                .insert(DebugLines { blas, bucket })
+                .insert(Name::new("BVH Debug Lines"));
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn update_debug(
+    debug: Res<BvhDebug>,
+    tlas: Res<Tlas>,
+    transforms: Query<&GlobalTransform, Without<DebugLines>>,
+    mut lines: Query<(
+        &DebugLines,
+        &Handle<Mesh>,
+        &Handle<StandardMaterial>,
+        &mut Visibility,
+        &mut Transform,
+        &mut GlobalTransform,
+    )>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut drawn_bvh: Local<Option<(Entity, usize)>>,
+) {
+    // the tlas is rebuilt every frame, the entity's bvh only needs redrawing when it changes
+    let instance = debug.entity.and_then(|e| tlas.get_instance(e));
+    let selected = instance.map(|instance| (instance.entity, instance.bvh_index));
+    let rebuild_blas = debug.is_changed() || selected != *drawn_bvh;
+    *drawn_bvh = selected;
+
+    let tlas_lines = (debug.is_changed() || tlas.is_changed()).then(|| {
+        if debug.tlas {
+            bucket_lines(&tlas_nodes(&tlas), &debug)
+        } else {
+            vec![Vec::new(); BUCKETS]
+        }
+    });
+    let blas_lines = rebuild_blas.then(|| match instance {
+        Some(instance) => bucket_lines(&bvh_nodes(&tlas.bvhs[instance.bvh_index]), &debug),
+        None => vec![Vec::new(); BUCKETS],
+    });
+    let blas_trans = instance.and_then(|instance| transforms.get(instance.entity).ok());
+
+    for (lines, mesh, material, mut visibility, mut trans, mut global) in lines.iter_mut() {
+        let buckets = if lines.blas { &blas_lines } else { &tlas_lines };
+        if let Some(buckets) = buckets {
+            let positions = buckets[lines.bucket].clone();
+            visibility.is_visible = !positions.is_empty();
+            if let Some(mesh) = meshes.get_mut(mesh) {
+                *mesh = line_mesh(positions);
+            }
+            // get_mut marks the material modified, so only when the color changes
+            let color = bucket_color(debug.color, lines.bucket);
+            if materials.get(material).map(|m| m.base_color) != Some(color) {
+                if let Some(material) = materials.get_mut(material) {
+                    material.base_color = color;
+                }
+            }
+        }
+        // set both, this runs after transforms are propagated
+        if let (true, Some(entity_trans)) = (lines.blas, blas_trans) {
+            *global = *entity_trans;
+            *trans = Transform::from(*entity_trans);
+        }
+    }
+}
+
+fn tlas_nodes(tlas: &Tlas) -> Vec<DebugNode> {
+    let mut nodes = Vec::new();
+    if !tlas.tlas_nodes.is_empty() && !tlas.blas.is_empty() {
+        collect_tlas_node(tlas, 0, 0, &mut nodes);
+    }
+    nodes
+}
+
+// returns the triangles under the node
+fn collect_tlas_node(tlas: &Tlas, index: usize, depth: u32, nodes: &mut Vec<DebugNode>) -> usize {
+    let node = &tlas.tlas_nodes[index];
+    let tris = if node.is_leaf() {
+        let instance = &tlas.blas[node.blas as usize];
+        tlas.bvhs[instance.bvh_index].tris.len()
+    } else {
+        collect_tlas_node(tlas, (node.left_right & 0xffff) as usize, depth + 1, nodes)
+            + collect_tlas_node(tlas, (node.left_right >> 16) as usize, depth + 1, nodes)
+    };
+    nodes.push(DebugNode {
+        aabb: node.aabb,
+        depth,
+        tris,
+    });
+    tris
+}
+
+fn bvh_nodes(bvh: &Bvh) -> Vec<DebugNode> {
+    let mut nodes = Vec::new();
+    if !bvh.tris.is_empty() {
+        collect_bvh_node(bvh, 0, 0, &mut nodes);
+    }
+    nodes
+}
+
+fn collect_bvh_node(bvh: &Bvh, index: usize, depth: u32, nodes: &mut Vec<DebugNode>) -> usize {
+    let node = &bvh.nodes[index];
+    let tris = if node.is_leaf() {
+        node.tri_count as usize
+    } else {
+        collect_bvh_node(bvh, node.left_first as usize, depth + 1, nodes)
+            + collect_bvh_node(bvh, node.left_first as usize + 1, depth + 1, nodes)
+    };
+    nodes.push(DebugNode {
+        aabb: node.aabb,
+        depth,
+        tris,
+    });
+    tris
+}
+
+// line list positions for each color bucket
+fn bucket_lines(nodes: &[DebugNode], debug: &BvhDebug) -> Vec<Vec<[f32; 3]>> {
+    let drawn = nodes
+        .iter()
+        .filter(|node| (debug.min_depth..=debug.max_depth).contains(&node.depth))
+        .collect::<Vec<_>>();
+    let cost = |node: &DebugNode| node.aabb.area() * node.tris as f32;
+    let max_cost = drawn.iter().map(|node| cost(node)).fold(0.0, f32::max);
+
+    let mut buckets = vec![Vec::new(); BUCKETS];
+    for node in drawn {
+        let bucket = match debug.color {
+            DebugColor::Level => node.depth as usize % BUCKETS,
+            DebugColor::SahCost if max_cost > 0.0 => {
+                ((cost(node) / max_cost * BUCKETS as f32) as usize).min(BUCKETS - 1)
+            }
+            DebugColor::SahCost => 0,
+        };
+        push_box(&node.aabb, &mut buckets[bucket]);
+    }
+    buckets
+}
+
+fn bucket_color(mode: DebugColor, bucket: usize) -> Color {
+    match mode {
+        DebugColor::Level => Color::hsl(bucket as f32 * 360.0 / BUCKETS as f32, 1.0, 0.5),
+        DebugColor::SahCost => heat_color(bucket as f32 / (BUCKETS - 1) as f32),
+    }
+}
+
+// 12 edges, each from a corner to the neighbor with one more axis at the max
+fn push_box(aabb: &Aabb, positions: &mut Vec<[f32; 3]>) {
+    let corner = |i: usize| {
+        [
+            if i & 1 != 0 { aabb.bmax.x } else { aabb.bmin.x },
+            if i & 2 != 0 { aabb.bmax.y } else { aabb.bmin.y },
+            if i & 4 != 0 { aabb.bmax.z } else { aabb.bmin.z },
+        ]
+    };
+    for i in 0..8 {
+        for axis in [1, 2, 4] {
+            if i & axis == 0 {
+                positions.push(corner(i));
+                positions.push(corner(i | axis));
+            }
+        }
+    }
+}
+
+fn line_mesh(positions: Vec<[f32; 3]>) -> Mesh {
+    let count = positions.len();
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    // the pbr pipeline expects these, unlit doesn't use them
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; count]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; count]);
+    mesh
+}
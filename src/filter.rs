@@ -0,0 +1,87 @@
+use crate::{bvh::BvhInstance, ray::Hit};
+use bevy::prelude::*;
+
+/// Layers an entity's bvh belongs to, a query only hits instances sharing a layer with its mask
+///
+/// Add next to BvhInit, on a BvhInitWithChildren root its copied to each child mesh
+#[derive(Component, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BvhLayers(pub u32);
+
+impl Default for BvhLayers {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BvhLayers {
+    pub const ALL: BvhLayers = BvhLayers(u32::MAX);
+    pub const NONE: BvhLayers = BvhLayers(0);
+
+    pub fn layer(index: u32) -> Self {
+        BvhLayers(1 << index)
+    }
+
+    pub fn with(self, index: u32) -> Self {
+        BvhLayers(self.0 | 1 << index)
+    }
+
+    pub fn without(self, index: u32) -> Self {
+        BvhLayers(self.0 & !(1 << index))
+    }
+}
+
+/// Limits which instances a query can hit, checked before descending into a blas
+#[derive(Copy, Clone)]
+pub struct QueryFilter<'a> {
+    pub mask: u32,
+    pub predicate: Option<&'a dyn Fn(Entity) -> bool>,
+    // called for each candidate triangle hit during traversal, false ignores it
+    pub any_hit: Option<&'a dyn Fn(&Hit) -> bool>,
+}
+
+impl Default for QueryFilter<'_> {
+    fn default() -> Self {
+        Self {
+            mask: u32::MAX,
+            predicate: None,
+            any_hit: None,
+        }
+    }
+}
+
+impl<'a> QueryFilter<'a> {
+    pub fn new(mask: u32) -> Self {
+        Self {
+            mask,
+            predicate: None,
+            any_hit: None,
+        }
+    }
+
+    pub fn with_predicate(mut self, predicate: &'a dyn Fn(Entity) -> bool) -> Self {
+        self.predicate = Some(predicate);
+        self
+    }
+
+    /// Lets traversal skip hits, RenderScene::accepts_hit passes through alpha masked texels
+    pub fn with_any_hit(mut self, any_hit: &'a dyn Fn(&Hit) -> bool) -> Self {
+        self.any_hit = Some(any_hit);
+        self
+    }
+
+    // map_or rather than is_none_or, which needs a newer rust than bevy 0.7
+    #[inline(always)]
+    #[allow(clippy::unnecessary_map_or)]
+    pub fn accepts(&self, instance: &BvhInstance) -> bool {
+        instance.layers & self.mask != 0
+            && self
+                .predicate
+                .map_or(true, |predicate| predicate(instance.entity))
+    }
+
+    #[inline(always)]
+    #[allow(clippy::unnecessary_map_or)]
+    pub fn accepts_hit(&self, hit: &Hit) -> bool {
+        self.any_hit.map_or(true, |any_hit| any_hit(hit))
+    }
+}
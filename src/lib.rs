@@ -1,14 +1,33 @@
-use bevy::{asset::LoadState, math::vec3, prelude::*, transform::TransformSystem};
-use std::time::Duration;
+use bevy::{
+    asset::LoadState,
+    math::{vec2, vec3},
+    prelude::*,
+    transform::TransformSystem,
+};
+use std::{sync::Arc, time::Duration};
 
 mod aabb;
 use aabb::*;
 mod assets;
 mod bvh;
 use bvh::*;
+#[cfg(feature = "camera")]
 mod camera;
+#[cfg(feature = "camera")]
 use camera::*;
+mod debug;
+mod filter;
+use filter::*;
+mod loader;
+use loader::*;
+#[cfg(feature = "camera")]
+mod offline;
+mod picking;
 mod ray;
+use ray::TraversalStats;
+mod raycast;
+mod render;
+use render::*;
 mod tlas;
 use tlas::*;
 mod tri;
@@ -16,9 +35,11 @@ use tri::*;
 
 pub mod prelude {
     pub use crate::{
-        aabb::Aabb, assets::*, bvh::*, camera::*, ray::*, tlas::*, tri::*, BvhInit, BvhPlugin,
-        BvhSystems,
+        aabb::Aabb, assets::*, bvh::*, debug::*, filter::*, loader::*, picking::*, ray::*,
+        raycast::*, render::*, tlas::*, tri::*, BvhInit, BvhPlugin, BvhSystems,
     };
+    #[cfg(feature = "camera")]
+    pub use crate::{camera::*, offline::*, BvhCameraPlugin};
 }
 
 const BIN_COUNT: usize = 8;
@@ -27,6 +48,7 @@ const BIN_COUNT: usize = 8;
 pub enum BvhSystems {
     Setup,
     Camera,
+    Picking,
 }
 
 pub struct BvhPlugin;
@@ -35,6 +57,8 @@ impl Plugin for BvhPlugin {
         app
             .init_resource::<BvhStats>()
             .init_resource::<Tlas>()
+            .add_asset::<Bvh>()
+            .init_asset_loader::<BvhLoader>()
             // .register_inspectable::<Bvh>()
             // .register_inspectable::<BvhCamera>()
             // .register_inspectable::<Tlas>()
@@ -54,43 +78,96 @@ impl Plugin for BvhPlugin {
                             .after(Self::spawn_bvh_with_children),
                     )
                     .with_system(Self::update_tlas.after(Self::update_bvh))
-            )
-            // CPU camera systems,
-            // TODO: make into feature, really should only ever be used for testing
-            .add_system_set_to_stage(
-                CoreStage::PostUpdate,
-                SystemSet::new()
-                    .label(BvhSystems::Camera)
-                    .after(BvhSystems::Setup)
-                    .with_system(camera_system::init_camera_image)
-                    .with_system(
-                        camera_system::update_camera.after(camera_system::init_camera_image),
-                    )
-                    .with_system(camera_system::render_camera.after(camera_system::update_camera))
-                    .with_system(display_camera.after(camera_system::render_camera)),
             );
+        // last so the frame totals include camera rendering
+        #[cfg(feature = "stats")]
+        app.add_system_to_stage(CoreStage::Last, Self::update_traversal_stats);
     }
 }
 
-// TODO: This will go way, for testing
-pub fn display_camera(mut commands: Commands, camera: Query<&BvhCamera, Added<BvhCamera>>) {
-    for camera in camera.iter() {
-        if let Some(image) = &camera.image {
+// Shared by the camera and picking plugins, only added by the first one
+pub(crate) fn add_render_scene(app: &mut App) {
+    if app.world.contains_resource::<RenderScene>() {
+        return;
+    }
+    app.init_resource::<RenderScene>().add_system_to_stage(
+        CoreStage::PostUpdate,
+        BvhPlugin::update_render_scene
+            .label(BvhSystems::Setup)
+            .after(BvhPlugin::update_tlas),
+    );
+}
+
+/// CPU rendered BvhCamera, add after BvhPlugin, really should only ever be used for testing
+#[cfg(feature = "camera")]
+pub struct BvhCameraPlugin;
+
+#[cfg(feature = "camera")]
+impl Plugin for BvhCameraPlugin {
+    fn build(&self, app: &mut App) {
+        add_render_scene(app);
+        app.add_system_set_to_stage(
+            CoreStage::PostUpdate,
+            SystemSet::new()
+                .label(BvhSystems::Camera)
+                .after(BvhSystems::Setup)
+                .with_system(camera_system::init_camera_image)
+                .with_system(camera_system::resize_camera.after(camera_system::init_camera_image))
+                .with_system(camera_system::update_camera.after(camera_system::resize_camera))
+                .with_system(camera_system::render_camera.after(camera_system::update_camera))
+                .with_system(display_camera.after(camera_system::render_camera)),
+        );
+    }
+}
+/// Ui node showing a BvhCamera's image, follows the camera's display position
+#[cfg(feature = "camera")]
+#[derive(Component)]
+pub struct BvhCameraDisplay(pub Entity);
+
+// Spawns, moves and removes the ui images as cameras change their display
+#[cfg(feature = "camera")]
+pub fn display_camera(
+    mut commands: Commands,
+    cameras: Query<(Entity, &BvhCamera)>,
+    mut displays: Query<(Entity, &BvhCameraDisplay, &mut Style, &mut UiImage)>,
+) {
+    let mut shown = Vec::new();
+    for (e, display, mut style, mut ui_image) in displays.iter_mut() {
+        let target = cameras
+            .get(display.0)
+            .ok()
+            .and_then(|(_, camera)| Some((camera.display?, camera.image.clone()?)));
+        match target {
+            Some((position, image)) => {
+                if style.position != position {
+                    style.position = position;
+                }
+                if ui_image.0 != image {
+                    ui_image.0 = image;
+                }
+                shown.push(display.0);
+            }
+            None => commands.entity(e).despawn_recursive(),
+        }
+    }
+
+    for (e, camera) in cameras.iter() {
+        if shown.contains(&e) {
+            continue;
+        }
+        if let (Some(position), Some(image)) = (camera.display, &camera.image) {
             commands
                 .spawn_bundle(ImageBundle {
                     style: Style {
                         align_self: AlignSelf::FlexEnd,
                         position_type: PositionType::Absolute,
-                        position: Rect {
-                            bottom: Val::Px(50.0),
-                            right: Val::Px(10.0),
-                            ..Default::default()
-                        },
+                        position,
                         ..default()
                     },
                     image: image.clone().into(),
                     ..default()
                 })
+                .insert(BvhCameraDisplay(e))
                 .insert(Name::new("BVH Image"));
         }
     }
@@ -99,32 +176,72 @@ pub fn display_camera(mut commands: Commands, camera: Query<&BvhCamera, Added<Bv
 #[derive(Default)]
 pub struct BvhStats {
     pub tri_count: usize,
+    // camera rays traced last frame, summed over every BvhCamera
     pub ray_count: f32,
+    // shadow and occlusion rays, not included in ray_count
+    pub secondary_ray_count: f32,
+    // work done by camera rays in heatmap mode last frame
+    pub camera_traversal: TraversalStats,
     pub camera_time: Duration,
+    // every tlas query last frame, gameplay raycasts, picking and cameras
+    #[cfg(feature = "stats")]
+    pub traversal: TraversalStats,
 }
 
 impl BvhPlugin {
+    #[cfg(feature = "stats")]
+    fn update_traversal_stats(mut stats: ResMut<BvhStats>, tlas: Res<Tlas>) {
+        stats.traversal = tlas.take_query_stats();
+    }
+
+    #[allow(clippy::type_complexity)]
     fn spawn_bvh(
         mut commands: Commands,
         meshes: Res<Assets<Mesh>>,
-        query: Query<(Entity, &Handle<Mesh>), With<BvhInit>>,
+        bvhs: Res<Assets<Bvh>>,
+        server: Res<AssetServer>,
+        query: Query<
+            (
+                Entity,
+                &Handle<Mesh>,
+                Option<&Handle<Bvh>>,
+                Option<&BvhLayers>,
+                Option<&Handle<StandardMaterial>>,
+            ),
+            With<BvhInit>,
+        >,
         mut tlas: ResMut<Tlas>,
         mut stats: ResMut<BvhStats>,
     ) {
-        for (e, handle) in query.iter() {
-            // let loaded = server.get_load_state(handle.id);
-            let mesh = meshes.get(handle).expect("Mesh not found");
-            let tris = parse_mesh(mesh);
+        for (e, handle, bvh_handle, layers, material) in query.iter() {
+            // a saved bvh skips the build, wait for it to load and fall back to the mesh if it fails,
+            // handles from Assets::add are never loaded by the server so check the assets first
+            let loaded = match bvh_handle {
+                Some(bvh_handle) => match bvhs.get(bvh_handle) {
+                    Some(bvh) => Some(bvh.clone()),
+                    None if server.get_load_state(bvh_handle) == LoadState::Failed => None,
+                    None => continue,
+                },
+                None => None,
+            };
+            let bvh = loaded.unwrap_or_else(|| {
+                let mesh = meshes.get(handle).expect("Mesh not found");
+                let (tris, tri_data) = parse_mesh(mesh);
+                Bvh::new_with_data(tris, tri_data)
+            });
             // mesh..ins(
             //     ATTRIBUTE_BLEND_COLOR,
             //     // The cube mesh has 24 vertices (6 faces, 4 vertices per face), so we insert one BlendColor for each
             //     vec![[1.0, 0.0, 0.0, 1.0]; 24],
             // );
 
-            stats.tri_count += tris.len();
+            stats.tri_count += bvh.tris.len();
 
-            let bvh_index = tlas.add_bvh(Bvh::new(tris));
-            tlas.add_instance(BvhInstance::new(e, bvh_index));
+            let bvh_index = tlas.add_bvh(bvh);
+            let mut instance = BvhInstance::new(e, bvh_index);
+            instance.layers = layers.copied().unwrap_or_default().0;
+            instance.material = material.cloned();
+            tlas.add_instance(instance);
             commands.entity(e).remove::<BvhInit>();
         }
     }
@@ -133,13 +250,18 @@ impl BvhPlugin {
     fn spawn_bvh_with_children(
         mut commands: Commands,
         meshes: Res<Assets<Mesh>>,
-        query: Query<(Entity, &BvhInitWithChildren)>,
-        children: Query<(Entity, Option<&Children>, Option<&Handle<Mesh>>)>,
+        query: Query<(Entity, &BvhInitWithChildren, Option<&BvhLayers>)>,
+        children: Query<(
+            Entity,
+            Option<&Children>,
+            Option<&Handle<Mesh>>,
+            Option<&Handle<StandardMaterial>>,
+        )>,
         server: Res<AssetServer>,
         mut stats: ResMut<BvhStats>,
         mut tlas: ResMut<Tlas>,
     ) {
-        for (root, scene) in query.iter() {
+        for (root, scene, layers) in query.iter() {
             let load_state = server.get_load_state(scene.0.id);
             if load_state != LoadState::Loaded {
                 continue;
@@ -147,7 +269,7 @@ impl BvhPlugin {
 
             let mut stack = vec![root];
             while let Some(e) = stack.pop() {
-                let (e, opt_children, opt_mesh) = children.get(e).unwrap();
+                let (e, opt_children, opt_mesh, opt_material) = children.get(e).unwrap();
                 if let Some(children) = opt_children {
                     for child in children.iter() {
                         stack.push(*child);
@@ -155,11 +277,17 @@ impl BvhPlugin {
                 }
                 if let Some(h_mesh) = opt_mesh {
                     let mesh = meshes.get(h_mesh).expect("Mesh not found");
-                    let tris = parse_mesh(mesh);
+                    let (tris, tri_data) = parse_mesh(mesh);
                     stats.tri_count += tris.len();
 
-                    let bvh_index = tlas.add_bvh(Bvh::new(tris));
-                    tlas.add_instance(BvhInstance::new(e, bvh_index));
+                    let bvh_index = tlas.add_bvh(Bvh::new_with_data(tris, tri_data));
+                    let mut instance = BvhInstance::new(e, bvh_index);
+                    instance.material = opt_material.cloned();
+                    if let Some(layers) = layers {
+                        instance.layers = layers.0;
+                        commands.entity(e).insert(*layers);
+                    }
+                    tlas.add_instance(instance);
                 }
             }
 
@@ -170,7 +298,10 @@ impl BvhPlugin {
     // TODO: both of these update system are incomplete, for now we are rebuilding every frame
     // for now working on speeding up ray intersection
     // will come back to this
-    pub fn update_bvh(query: Query<&GlobalTransform>, mut tlas: ResMut<Tlas>) {
+    pub fn update_bvh(
+        query: Query<(&GlobalTransform, Option<&BvhLayers>)>,
+        mut tlas: ResMut<Tlas>,
+    ) {
         // moved fn into tlas self to since it needed 2 mutable refs within the tlas
         tlas.update_bvh_instances(&query);
     }
@@ -178,24 +309,95 @@ impl BvhPlugin {
     pub fn update_tlas(mut tlas: ResMut<Tlas>) {
         tlas.build();
     }
+
+    // Only added with the camera or picking plugins, see add_render_scene
+    // The resource is only marked changed when something differs, the path tracer resets on it
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_render_scene(
+        mut scene: ResMut<RenderScene>,
+        tlas: Res<Tlas>,
+        materials: Res<Assets<StandardMaterial>>,
+        images: Res<Assets<Image>>,
+        mut image_events: EventReader<AssetEvent<Image>>,
+        directional_lights: Query<(&DirectionalLight, &GlobalTransform)>,
+        point_lights: Query<(&PointLight, &GlobalTransform)>,
+        moved: Query<Entity, Changed<GlobalTransform>>,
+        ambient: Res<AmbientLight>,
+        clear_color: Res<ClearColor>,
+    ) {
+        // textures are only copied again when they change
+        let mut stale_textures = Vec::new();
+        for event in image_events.iter() {
+            if let AssetEvent::Modified { handle } | AssetEvent::Removed { handle } = event {
+                stale_textures.push(handle.id);
+            }
+        }
+
+        let mut next = RenderScene {
+            ambient: linear_rgb(ambient.color) * ambient.brightness,
+            background: linear_rgb(clear_color.0),
+            ..Default::default()
+        };
+        for instance in &tlas.blas {
+            let material = match instance.material.as_ref().and_then(|h| materials.get(h)) {
+                Some(material) => SurfaceMaterial::from(material),
+                None => continue,
+            };
+            if let Some(id) = material.base_color_texture {
+                if !next.textures.contains_key(&id) {
+                    let texture = match scene.textures.get(&id) {
+                        Some(texture) if !stale_textures.contains(&id) => Some(texture.clone()),
+                        _ => images
+                            .get(id)
+                            .and_then(CpuTexture::from_image)
+                            .map(Arc::new),
+                    };
+                    if let Some(texture) = texture {
+                        next.textures.insert(id, texture);
+                    }
+                }
+            }
+            next.materials.insert(instance.entity, material);
+        }
+        for (light, trans) in directional_lights.iter() {
+            next.lights.push(SceneLight::directional(light, trans));
+        }
+        for (light, trans) in point_lights.iter() {
+            next.lights.push(SceneLight::point(light, trans));
+        }
+
+        if *scene != next {
+            *scene = next;
+        } else if moved.iter().any(|e| tlas.get_instance(e).is_some()) {
+            scene.set_changed();
+        }
+    }
 }
 
+#[cfg(feature = "camera")]
 pub mod camera_system {
     use super::BvhCamera;
-    use crate::{tlas::Tlas, BvhStats};
+    use crate::{
+        ray::TraversalStats,
+        render::{render_tiles, sample_pixel, to_srgb_bytes, RenderMode, RenderScene},
+        tlas::Tlas,
+        BvhStats,
+    };
     use bevy::{
-        math::vec3,
         prelude::*,
         render::render_resource::{Extent3d, TextureDimension, TextureFormat},
         utils::Instant,
     };
-    use rayon::prelude::*;
 
     pub fn init_camera_image(
         mut query: Query<&mut BvhCamera, Added<BvhCamera>>,
         mut images: ResMut<Assets<Image>>,
     ) {
         for mut camera in query.iter_mut() {
+            // keep the target the camera was spawned with
+            if camera.image.is_some() {
+                continue;
+            }
             let image = images.add(Image::new(
                 Extent3d {
                     width: camera.width as u32,
@@ -210,66 +412,179 @@ pub mod camera_system {
         }
     }
 
-    pub fn update_camera(mut camera_query: Query<(&mut BvhCamera, &GlobalTransform)>) {
-        for (mut camera, trans) in camera_query.iter_mut() {
+    // Checks sizes every frame rather than on Changed, render_camera changes every camera anyway
+    pub fn resize_camera(
+        mut query: Query<&mut BvhCamera>,
+        windows: Option<Res<Windows>>,
+        mut images: ResMut<Assets<Image>>,
+    ) {
+        let window = windows.as_ref().and_then(|windows| windows.get_primary());
+        for mut camera in query.iter_mut() {
+            if let (Some(scale), Some(window)) = (camera.follow_window, window) {
+                let width = ((window.physical_width() as f32 * scale) as u32).max(1);
+                let height = ((window.physical_height() as f32 * scale) as u32).max(1);
+                if camera.width != width || camera.height != height {
+                    camera.width = width;
+                    camera.height = height;
+                }
+            }
+
+            let image = match camera.image.as_ref().and_then(|image| images.get_mut(image)) {
+                Some(image) => image,
+                None => continue,
+            };
+            let size = image.texture_descriptor.size;
+            if size.width != camera.width || size.height != camera.height {
+                image.resize(Extent3d {
+                    width: camera.width,
+                    height: camera.height,
+                    depth_or_array_layers: 1,
+                });
+                camera.reset_accumulation();
+            }
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn update_camera(
+        mut camera_query: Query<(
+            &mut BvhCamera,
+            &GlobalTransform,
+            Option<&Camera>,
+            Option<&PerspectiveProjection>,
+            Option<&OrthographicProjection>,
+        )>,
+        windows: Option<Res<Windows>>,
+        images: Res<Assets<Image>>,
+    ) {
+        for (mut camera, trans, bevy_camera, perspective, orthographic) in camera_query.iter_mut() {
+            // on a bevy camera the projection is sized to its target like bevy does,
+            // so the image shows the same view, otherwise to the image
+            let view_size = match (bevy_camera, &windows) {
+                (Some(bevy_camera), Some(windows)) => bevy_camera
+                    .target
+                    .get_logical_size(windows, &images)
+                    .filter(|size| size.x > 0.0 && size.y > 0.0),
+                _ => None,
+            }
+            .unwrap_or_else(|| Vec2::new(camera.width as f32, camera.height as f32));
+
+            // set every frame so the aspect ratio follows the view's size
+            if let Some(projection) = perspective {
+                camera.set_projection_sized(projection, view_size);
+            } else if let Some(projection) = orthographic {
+                camera.set_projection_sized(projection, view_size);
+            } else {
+                camera.set_projection_sized(&PerspectiveProjection::default(), view_size);
+            }
             camera.update(trans);
         }
     }
 
     pub fn render_camera(
-        camera_query: Query<&BvhCamera>,
+        mut camera_query: Query<&mut BvhCamera>,
         mut images: ResMut<Assets<Image>>,
         mut stats: ResMut<BvhStats>,
-
+        scene: Res<RenderScene>,
         tlas: Res<Tlas>,
     ) {
-        if let Ok(camera) = camera_query.get_single() {
-            if let Some(image) = &camera.image {
-                let start = Instant::now();
-                let image = images.get_mut(image).unwrap();
-
-                // TODO: Make this acutally tilings, currenty this just takes a slice pixels in a row
-                const PIXEL_TILE_COUNT: usize = 64;
-                const PIXEL_TILE: usize = 4 * PIXEL_TILE_COUNT;
-                image
-                    .data
-                    .par_chunks_mut(PIXEL_TILE)
-                    .enumerate()
-                    .for_each(|(i, pixels)| {
-                        for pixel_offset in 0..(pixels.len() / 4) {
-                            let index = i * PIXEL_TILE_COUNT + pixel_offset;
-                            let offset = pixel_offset * 4;
-
-                            let x = index as u32 % camera.width;
-                            let y = index as u32 / camera.width;
-                            let u = x as f32 / camera.width as f32;
-                            let v = y as f32 / camera.height as f32;
-                            // TODO: Revisit multiple samples later
-                            // if samples > 0 {
-                            //     u += rng.gen::<f32>() / camera.width as f32;
-                            //     v += rng.gen::<f32>() / camera.height as f32;
-                            // }
-
-                            // TODO: flip v since image is upside down, figure out why
-                            let mut ray = camera.get_ray(u, 1.0 - v);
-                            let color = if let Some(hit) = ray.intersect_tlas(&tlas) {
-                                vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v)) * 255.0
-                            } else {
-                                Vec3::ZERO
-                            };
-
-                            pixels[offset] = color.x as u8;
-                            pixels[offset + 1] = color.y as u8;
-                            pixels[offset + 2] = color.z as u8;
-                            pixels[offset + 3] = 255;
-                        }
-                    });
+        let start = Instant::now();
+        stats.ray_count = 0.0;
+        stats.secondary_ray_count = 0.0;
+        stats.camera_traversal = TraversalStats::default();
+        for camera in camera_query.iter_mut() {
+            let camera = camera.into_inner();
+            // reset even when skipping this frame, so the change isn't missed
+            if scene.is_changed() {
+                camera.reset_accumulation();
+            }
+            if !camera.should_render() {
+                continue;
+            }
+            let image = match camera.image.as_ref().and_then(|image| images.get_mut(image)) {
+                // rgba8 target sized to the camera
+                Some(image) if image.data.len() == (camera.width * camera.height * 4) as usize => {
+                    image
+                }
+                _ => continue,
+            };
+            render_image(camera, image, &scene, &tlas, &mut stats);
+        }
+        stats.camera_time = start.elapsed();
+    }
 
-                stats.ray_count =
-                    camera.width as f32 * camera.height as f32 * camera.samples as f32;
-                stats.camera_time = start.elapsed();
+    // adds the rays traced to stats
+    fn render_image(
+        camera: &mut BvhCamera,
+        image: &mut Image,
+        scene: &RenderScene,
+        tlas: &Tlas,
+        stats: &mut BvhStats,
+    ) {
+        let mut accumulation = camera.take_accumulation();
+        let mut aovs = camera.take_aovs();
+        let frame = camera.accumulated_frames();
+
+        // tiles render into their own buffers, then get copied into place
+        let rendered = render_tiles(camera, frame as u64, |x, y, tile| {
+            let index = (y * camera.width + x) as usize;
+            let aov = (!aovs.is_empty()).then(|| {
+                let u = (x as f32 + 0.5) / camera.width as f32;
+                let v = (y as f32 + 0.5) / camera.height as f32;
+                camera.trace_aov(u, v, scene, tlas)
+            });
+            let color = sample_pixel(
+                camera,
+                scene,
+                tlas,
+                x,
+                y,
+                &mut tile.rng,
+                &mut tile.secondary_rays,
+                &mut tile.traversal,
+            );
+            let mut sum = accumulation[index];
+            let pixel = match camera.mode {
+                RenderMode::Barycentric | RenderMode::Heatmap => {
+                    let color = color * 255.0;
+                    [color.x as u8, color.y as u8, color.z as u8, 255]
+                }
+                RenderMode::Shaded => to_srgb_bytes(color),
+                RenderMode::PathTrace => {
+                    sum += color;
+                    to_srgb_bytes(sum / (frame + 1) as f32)
+                }
+            };
+            (pixel, sum, aov)
+        });
+
+        #[cfg(feature = "stats")]
+        let mut queries = TraversalStats::default();
+        for (tile, pixels, state) in rendered {
+            #[cfg(feature = "stats")]
+            {
+                queries += state.queries;
+            }
+            for ((x, y), (pixel, sum, aov)) in tile.pixels().zip(pixels) {
+                let index = (y * camera.width + x) as usize;
+                image.data[index * 4..index * 4 + 4].copy_from_slice(&pixel);
+                accumulation[index] = sum;
+                if let Some(aov) = aov {
+                    aovs[index] = aov;
+                }
             }
+            stats.secondary_ray_count += state.secondary_rays as f32;
+            stats.camera_traversal += state.traversal;
         }
+        #[cfg(feature = "stats")]
+        tlas.add_query_stats(queries);
+
+        let path_trace = camera.mode == RenderMode::PathTrace;
+        camera.finish_accumulation(accumulation, path_trace);
+        let aov_rays = if aovs.is_empty() { 0 } else { 1 };
+        camera.finish_aovs(aovs);
+        stats.ray_count +=
+            camera.width as f32 * camera.height as f32 * (camera.samples + aov_rays) as f32;
     }
 }
 
@@ -280,7 +595,8 @@ pub struct BvhInit;
 pub struct BvhInitWithChildren(pub Handle<Scene>);
 
 // TODO: We dont really want to copy the all tris, find better way
-pub fn parse_mesh(mesh: &Mesh) -> Vec<Tri> {
+// Tri data is left empty when the mesh has no normals or uvs
+pub fn parse_mesh(mesh: &Mesh) -> (Vec<Tri>, Vec<TriData>) {
     match mesh.primitive_topology() {
         bevy::render::mesh::PrimitiveTopology::TriangleList => {
             let indexes = match mesh.indices().expect("No Indices") {
@@ -299,20 +615,79 @@ pub fn parse_mesh(mesh: &Mesh) -> Vec<Tri> {
             }
             .collect::<Vec<_>>();
 
+            let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+                Some(bevy::render::mesh::VertexAttributeValues::Float32x3(vec)) => vec
+                    .iter()
+                    .map(|vec| vec3(vec[0], vec[1], vec[2]))
+                    .collect::<Vec<_>>(),
+                _ => Vec::new(),
+            };
+            let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+                Some(bevy::render::mesh::VertexAttributeValues::Float32x2(vec)) => {
+                    vec.iter().map(|vec| vec2(vec[0], vec[1])).collect::<Vec<_>>()
+                }
+                _ => Vec::new(),
+            };
+
             let mut triangles = Vec::with_capacity(indexes.len() / 3);
-            for tri_indexes in indexes.chunks(3)
-             {
-                let v0 = verts[tri_indexes[0] as usize];
-                let v1 = verts[tri_indexes[1] as usize];
-                let v2 = verts[tri_indexes[2] as usize];
-                triangles.push(Tri::new(
-                    vec3(v0[0], v0[1], v0[2]),
-                    vec3(v1[0], v1[1], v1[2]),
-                    vec3(v2[0], v2[1], v2[2]),
-                ));
+            let mut tri_data = Vec::new();
+            for tri_indexes in indexes.chunks(3) {
+                let i0 = tri_indexes[0] as usize;
+                let i1 = tri_indexes[1] as usize;
+                let i2 = tri_indexes[2] as usize;
+                let tri = Tri::new(verts[i0], verts[i1], verts[i2]);
+                if !normals.is_empty() || !uvs.is_empty() {
+                    // flat normals when only the uvs are there
+                    let face_normal = (tri.vertex1 - tri.vertex0)
+                        .cross(tri.vertex2 - tri.vertex0)
+                        .normalize_or_zero();
+                    let normal = |i: usize| normals.get(i).copied().unwrap_or(face_normal);
+                    let uv = |i: usize| uvs.get(i).copied().unwrap_or_default();
+                    tri_data.push(TriData {
+                        normal0: normal(i0),
+                        normal1: normal(i1),
+                        normal2: normal(i2),
+                        uv0: uv(i0),
+                        uv1: uv(i1),
+                        uv2: uv(i2),
+                    });
+                }
+                triangles.push(tri);
             }
-            triangles
+            (triangles, tri_data)
         }
         _ => todo!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_bvh_from_added_asset() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(bevy::asset::AssetPlugin)
+            .add_asset::<Mesh>()
+            .add_asset::<Bvh>()
+            .add_asset::<StandardMaterial>()
+            .init_resource::<Tlas>()
+            .init_resource::<BvhStats>()
+            .add_system(BvhPlugin::spawn_bvh);
+
+        let mesh = Mesh::from(shape::Cube::default());
+        let (tris, tri_data) = parse_mesh(&Mesh::from(shape::Icosphere::default()));
+        let saved = Bvh::new_with_data(tris, tri_data);
+        let saved_tris = saved.tris.len();
+        let mesh = app.world.resource_mut::<Assets<Mesh>>().add(mesh);
+        let saved = app.world.resource_mut::<Assets<Bvh>>().add(saved);
+        let entity = app.world.spawn().insert_bundle((mesh, saved, BvhInit)).id();
+        app.update();
+
+        let tlas = app.world.resource::<Tlas>();
+        let instance = tlas.get_instance(entity).expect("bvh wasn't spawned");
+        assert_eq!(tlas.bvhs[instance.bvh_index].tris.len(), saved_tris);
+        assert!(app.world.get::<BvhInit>(entity).is_none());
+    }
+}
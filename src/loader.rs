@@ -0,0 +1,299 @@
+use crate::{
+    bvh::{Bvh, BvhNode},
+    tri::{Tri, TriData},
+};
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    utils::BoxedFuture,
+};
+use bytemuck::Pod;
+use std::{fs, mem::size_of, path::Path};
+use thiserror::Error;
+
+// Layout, all little endian:
+// magic "SBVH", version, then node, tri, tri data and triangle index counts as u32s
+// followed by each section as it's laid out in memory, indices stored as u32s
+const MAGIC: [u8; 4] = *b"SBVH";
+/// Bumped whenever the layout of the file, or of BvhNode, Tri or TriData, changes
+pub const BVH_FILE_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+
+#[derive(Debug, Error)]
+pub enum BvhFileError {
+    #[error("bvh file io failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a bvh file")]
+    Magic,
+    #[error("bvh file version {0} isn't supported, expected {BVH_FILE_VERSION}")]
+    Version(u32),
+    #[error("bvh file is {actual} bytes, its header says {expected}")]
+    Size { expected: u64, actual: u64 },
+    #[error("bvh file is invalid: {0}")]
+    Invalid(&'static str),
+}
+
+impl Bvh {
+    /// Writes the built bvh to a .bvh file, load it back with Bvh::load or the asset server
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BvhFileError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Reads a .bvh file, each section is copied out of the file's bytes into the Bvh's own Vecs
+    pub fn load(path: impl AsRef<Path>) -> Result<Bvh, BvhFileError> {
+        Bvh::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let indices = self
+            .triangle_indexs
+            .iter()
+            .map(|i| *i as u32)
+            .collect::<Vec<_>>();
+        let counts = [
+            self.nodes.len(),
+            self.tris.len(),
+            self.tri_data.len(),
+            indices.len(),
+        ];
+
+        let mut bytes = Vec::with_capacity(
+            HEADER_SIZE
+                + self.nodes.len() * size_of::<BvhNode>()
+                + self.tris.len() * size_of::<Tri>()
+                + self.tri_data.len() * size_of::<TriData>()
+                + indices.len() * size_of::<u32>(),
+        );
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&BVH_FILE_VERSION.to_le_bytes());
+        for count in counts {
+            bytes.extend_from_slice(&(count as u32).to_le_bytes());
+        }
+        push_section(&mut bytes, &self.nodes);
+        push_section(&mut bytes, &self.tris);
+        push_section(&mut bytes, &self.tri_data);
+        push_section(&mut bytes, &indices);
+        bytes
+    }
+
+    /// Reads a bvh written by to_bytes, checking it can be traversed safely
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bvh, BvhFileError> {
+        if bytes.len() < HEADER_SIZE || bytes[0..4] != MAGIC {
+            return Err(BvhFileError::Magic);
+        }
+        let header = bytes[4..HEADER_SIZE]
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect::<Vec<_>>();
+        if header[0] != BVH_FILE_VERSION {
+            return Err(BvhFileError::Version(header[0]));
+        }
+        let [node_count, tri_count, data_count, index_count] =
+            [header[1], header[2], header[3], header[4]].map(|count| count as u64);
+        let expected = HEADER_SIZE as u64
+            + node_count * size_of::<BvhNode>() as u64
+            + tri_count * size_of::<Tri>() as u64
+            + data_count * size_of::<TriData>() as u64
+            + index_count * size_of::<u32>() as u64;
+        if expected != bytes.len() as u64 {
+            return Err(BvhFileError::Size {
+                expected,
+                actual: bytes.len() as u64,
+            });
+        }
+
+        // each section is a single copy into place, which also takes care of the buffer's alignment
+        let mut rest = &bytes[HEADER_SIZE..];
+        let nodes: Vec<BvhNode> = take_section(&mut rest, node_count);
+        let tris: Vec<Tri> = take_section(&mut rest, tri_count);
+        let tri_data: Vec<TriData> = take_section(&mut rest, data_count);
+        let indices: Vec<u32> = take_section(&mut rest, index_count);
+
+        let bvh = Bvh {
+            nodes,
+            tris,
+            tri_data,
+            triangle_indexs: indices.into_iter().map(|i| i as usize).collect(),
+        };
+        bvh.validate()?;
+        Ok(bvh)
+    }
+
+    // Everything the traversals index without checking
+    fn validate(&self) -> Result<(), BvhFileError> {
+        if self.nodes.is_empty() {
+            return Err(BvhFileError::Invalid("no nodes"));
+        }
+        if !self.tri_data.is_empty() && self.tri_data.len() != self.tris.len() {
+            return Err(BvhFileError::Invalid(
+                "tri data doesn't match the triangles",
+            ));
+        }
+        if self.triangle_indexs.len() != self.tris.len() {
+            return Err(BvhFileError::Invalid(
+                "triangle indices don't match the triangles",
+            ));
+        }
+        if self.triangle_indexs.iter().any(|i| *i >= self.tris.len()) {
+            return Err(BvhFileError::Invalid("triangle index out of range"));
+        }
+        // built from an empty mesh, traversal skips these without looking at the nodes
+        if self.tris.is_empty() {
+            return Ok(());
+        }
+
+        // only nodes reachable from the root are used, the builder leaves the second one empty
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let first = node.left_first as usize;
+            if node.is_leaf() {
+                if first + node.tri_count as usize > self.triangle_indexs.len() {
+                    return Err(BvhFileError::Invalid("leaf triangles out of range"));
+                }
+            } else {
+                // children always come after their parent, so this can't loop
+                if first <= index || first + 1 >= self.nodes.len() {
+                    return Err(BvhFileError::Invalid("node children out of range"));
+                }
+                stack.push(first);
+                stack.push(first + 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sections are all 4 byte f32 and u32 words, so only big endian machines need to swap them
+fn push_section<T: Pod>(bytes: &mut Vec<u8>, items: &[T]) {
+    if cfg!(target_endian = "little") {
+        bytes.extend_from_slice(bytemuck::cast_slice(items));
+    } else {
+        for word in bytemuck::cast_slice::<T, u32>(items) {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+}
+
+fn take_section<T: Pod>(bytes: &mut &[u8], count: u64) -> Vec<T> {
+    let (section, rest) = bytes.split_at(count as usize * size_of::<T>());
+    *bytes = rest;
+    let mut items: Vec<T> = bytemuck::pod_collect_to_vec(section);
+    if cfg!(target_endian = "big") {
+        for word in bytemuck::cast_slice_mut::<T, u32>(&mut items) {
+            *word = u32::from_le(*word);
+        }
+    }
+    items
+}
+
+/// Loads .bvh files saved with Bvh::save, added by BvhPlugin
+///
+/// The file is read through bevy's AssetIo, then copied into the Bvh same as Bvh::load.
+/// Put the Handle<Bvh> on an entity along with BvhInit to skip building it from the mesh
+#[derive(Default)]
+pub struct BvhLoader;
+
+impl AssetLoader for BvhLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let bvh = Bvh::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(bvh));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bvh"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_mesh, ray::Ray};
+    use bevy::prelude::*;
+
+    fn sphere() -> Bvh {
+        let (tris, tri_data) = parse_mesh(&Mesh::from(shape::UVSphere::default()));
+        Bvh::new_with_data(tris, tri_data)
+    }
+
+    fn first_hit(bvh: &Bvh) -> Option<f32> {
+        let mut ray = Ray::new(Vec3::Z * 5.0, -Vec3::Z);
+        ray.intersect_bvh(bvh, Entity::from_raw(0));
+        ray.hit.map(|hit| hit.distance)
+    }
+
+    #[test]
+    fn round_trip() {
+        let bvh = sphere();
+        let loaded = Bvh::from_bytes(&bvh.to_bytes()).unwrap();
+        assert_eq!(loaded.nodes.len(), bvh.nodes.len());
+        assert_eq!(loaded.triangle_indexs, bvh.triangle_indexs);
+        assert!(first_hit(&bvh).is_some());
+        assert_eq!(first_hit(&loaded), first_hit(&bvh));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let bvh = sphere();
+        let path = std::env::temp_dir().join(format!("sphere_{}.bvh", std::process::id()));
+        bvh.save(&path).unwrap();
+        let loaded = Bvh::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(first_hit(&loaded.unwrap()), first_hit(&bvh));
+    }
+
+    #[test]
+    fn empty_mesh() {
+        let bvh = Bvh::new(Vec::new());
+        assert!(first_hit(&bvh).is_none());
+        let path = std::env::temp_dir().join(format!("empty_{}.bvh", std::process::id()));
+        bvh.save(&path).unwrap();
+        let loaded = Bvh::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(first_hit(&loaded.unwrap()).is_none());
+    }
+
+    #[test]
+    fn header_is_little_endian() {
+        let bytes = sphere().to_bytes();
+        assert_eq!(bytes[0..4], MAGIC);
+        assert_eq!(bytes[4..8], [BVH_FILE_VERSION as u8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = sphere().to_bytes();
+        assert!(matches!(
+            Bvh::from_bytes(&bytes[..10]),
+            Err(BvhFileError::Magic)
+        ));
+
+        let mut version = bytes.clone();
+        version[4] += 1;
+        assert!(matches!(
+            Bvh::from_bytes(&version),
+            Err(BvhFileError::Version(_))
+        ));
+
+        assert!(matches!(
+            Bvh::from_bytes(&bytes[..bytes.len() - 4]),
+            Err(BvhFileError::Size { .. })
+        ));
+
+        // root pointing at itself
+        let mut looping = bytes;
+        let left_first = HEADER_SIZE + 24;
+        looping[left_first..left_first + 8].fill(0);
+        assert!(matches!(
+            Bvh::from_bytes(&looping),
+            Err(BvhFileError::Invalid(_))
+        ));
+    }
+}
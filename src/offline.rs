@@ -0,0 +1,145 @@
+use crate::{
+    camera::BvhCamera,
+    render::{render_tiles, sample_pixel, to_srgb_bytes, RenderMode, RenderScene},
+    tlas::Tlas,
+};
+use bevy::prelude::*;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Everything render_to_buffer needs besides the tlas and camera
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub scene: RenderScene,
+    // same seed gives the same image
+    pub seed: u64,
+    // passes averaged together, each traces camera.samples rays per pixel
+    pub passes: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            scene: RenderScene::default(),
+            seed: 0,
+            passes: 1,
+        }
+    }
+}
+
+/// Linear float image, rows from the top
+#[derive(Debug, Clone)]
+pub struct RenderBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+    // barycentric and heatmap renders are display colors already, so they skip tonemapping
+    pub tonemap: bool,
+}
+
+impl RenderBuffer {
+    /// Tonemapped and sRGB encoded, same as the image BvhCamera renders into
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|color| {
+                if self.tonemap {
+                    to_srgb_bytes(*color)
+                } else {
+                    let [r, g, b, _] = Color::rgb_linear(color.x, color.y, color.z).as_rgba_f32();
+                    [
+                        (r.clamp(0.0, 1.0) * 255.0) as u8,
+                        (g.clamp(0.0, 1.0) * 255.0) as u8,
+                        (b.clamp(0.0, 1.0) * 255.0) as u8,
+                        255,
+                    ]
+                }
+            })
+            .collect()
+    }
+
+    pub fn write_png(&self, path: impl AsRef<Path>) -> image::ImageResult<()> {
+        image::save_buffer(
+            path,
+            &self.to_rgba8(),
+            self.width,
+            self.height,
+            image::ColorType::Rgba8,
+        )
+    }
+
+    /// Portable float map, keeps the full hdr values for comparing renders
+    pub fn write_pfm(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // negative scale means little endian
+        write!(file, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        // pfm rows go from the bottom up
+        for row in self.pixels.chunks(self.width as usize).rev() {
+            for color in row {
+                for channel in color.to_array() {
+                    file.write_all(&channel.to_le_bytes())?;
+                }
+            }
+        }
+        file.flush()
+    }
+}
+
+/// Renders the camera's view without a bevy app, the camera needs to be updated with its transform
+pub fn render_to_buffer(
+    tlas: &Tlas,
+    camera: &BvhCamera,
+    settings: &RenderSettings,
+) -> RenderBuffer {
+    let passes = settings.passes.max(1);
+    let mut pixels = vec![Vec3::ZERO; (camera.width * camera.height) as usize];
+    let rendered = render_tiles(camera, settings.seed, |x, y, tile| {
+        let mut color = Vec3::ZERO;
+        for _ in 0..passes {
+            color += sample_pixel(
+                camera,
+                &settings.scene,
+                tlas,
+                x,
+                y,
+                &mut tile.rng,
+                &mut tile.secondary_rays,
+                &mut tile.traversal,
+            );
+        }
+        color / passes as f32
+    });
+
+    #[cfg(feature = "stats")]
+    tlas.add_query_stats(
+        rendered
+            .iter()
+            .fold(Default::default(), |mut queries, (_, _, tile)| {
+                queries += tile.queries;
+                queries
+            }),
+    );
+
+    let tonemap = !matches!(camera.mode, RenderMode::Barycentric | RenderMode::Heatmap);
+    for (tile, colors, _) in rendered {
+        for ((x, y), color) in tile.pixels().zip(colors) {
+            pixels[(y * camera.width + x) as usize] = if tonemap {
+                color
+            } else {
+                // written to the realtime image as is, so treat them as srgb
+                let [r, g, b, _] = Color::rgb(color.x, color.y, color.z).as_linear_rgba_f32();
+                Vec3::new(r, g, b)
+            };
+        }
+    }
+
+    RenderBuffer {
+        width: camera.width,
+        height: camera.height,
+        pixels,
+        tonemap,
+    }
+}
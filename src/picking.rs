@@ -0,0 +1,154 @@
+use crate::{
+    filter::QueryFilter,
+    raycast::{BvhRaycast, RaycastHit},
+    render::RenderScene,
+    tlas::Tlas,
+    BvhSystems,
+};
+use bevy::{
+    ecs::entity::Entities,
+    prelude::*,
+    render::camera::{ActiveCamera, Camera3d},
+};
+
+/// Mouse picking from the active 3d camera using the tlas
+pub struct BvhPickingPlugin;
+
+impl Plugin for BvhPickingPlugin {
+    fn build(&self, app: &mut App) {
+        // materials for seeing through alpha masks
+        crate::add_render_scene(app);
+        app.add_event::<PickEvent>()
+            .init_resource::<BvhPicking>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                update_picking
+                    .label(BvhSystems::Picking)
+                    .after(BvhSystems::Setup),
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PickEvent {
+    HoverStart(RaycastHit),
+    HoverEnd(Entity),
+    Clicked(RaycastHit),
+}
+
+/// Marks the entity currently under the cursor
+#[derive(Component)]
+pub struct Hovered;
+
+pub struct BvhPicking {
+    pub enabled: bool,
+    // only instances sharing a layer with this mask can be picked
+    pub mask: u32,
+    pub button: MouseButton,
+    // last hit under the cursor
+    pub hit: Option<RaycastHit>,
+}
+
+impl Default for BvhPicking {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mask: u32::MAX,
+            button: MouseButton::Left,
+            hit: None,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_picking(
+    mut commands: Commands,
+    entities: &Entities,
+    mut picking: ResMut<BvhPicking>,
+    mut events: EventWriter<PickEvent>,
+    raycast: BvhRaycast,
+    active_camera: Res<ActiveCamera<Camera3d>>,
+    mouse: Res<Input<MouseButton>>,
+    scene: Res<RenderScene>,
+    tlas: Res<Tlas>,
+) {
+    // see through alpha masked texels, same as the cpu renderer
+    let any_hit = |hit: &_| scene.accepts_hit(hit, &tlas);
+    // the tlas keeps instances of despawned entities until it's rebuilt
+    let alive = |entity| entities.contains(entity);
+    let hit = match active_camera.get() {
+        Some(camera) if picking.enabled => raycast.from_cursor(
+            camera,
+            &QueryFilter::new(picking.mask)
+                .with_predicate(&alive)
+                .with_any_hit(&any_hit),
+        ),
+        _ => None,
+    };
+
+    let previous = picking.hit.map(|hit| hit.entity);
+    let current = hit.map(|hit| hit.entity);
+    if previous != current {
+        if let Some(entity) = previous {
+            // the hovered entity may have been despawned since last frame
+            if entities.contains(entity) {
+                commands.entity(entity).remove::<Hovered>();
+            }
+            events.send(PickEvent::HoverEnd(entity));
+        }
+        if let Some(hit) = hit {
+            commands.entity(hit.entity).insert(Hovered);
+            events.send(PickEvent::HoverStart(hit));
+        }
+    }
+
+    if let Some(hit) = hit {
+        if mouse.just_pressed(picking.button) {
+            events.send(PickEvent::Clicked(hit));
+        }
+    }
+    picking.hit = hit;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::event::{Events, ManualEventReader};
+
+    #[test]
+    fn hovered_entity_despawned() {
+        let mut world = World::new();
+        world.init_resource::<Tlas>();
+        world.init_resource::<RenderScene>();
+        world.init_resource::<ActiveCamera<Camera3d>>();
+        world.init_resource::<Input<MouseButton>>();
+        world.init_resource::<Events<PickEvent>>();
+
+        let entity = world.spawn().insert(Hovered).id();
+        world.insert_resource(BvhPicking {
+            hit: Some(RaycastHit {
+                entity,
+                position: Vec3::ZERO,
+                normal: Vec3::Z,
+                distance: 1.0,
+                tri_index: 0,
+            }),
+            ..Default::default()
+        });
+        world.despawn(entity);
+
+        let mut stage = SystemStage::single(update_picking);
+        stage.run(&mut world);
+
+        assert!(world.resource::<BvhPicking>().hit.is_none());
+        let events = world.resource::<Events<PickEvent>>();
+        let sent = ManualEventReader::<PickEvent>::default()
+            .iter(events)
+            .map(|event| match event {
+                PickEvent::HoverEnd(ended) => *ended == entity,
+                _ => false,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sent, [true]);
+    }
+}
@@ -1,10 +1,16 @@
 use std::mem::swap;
+#[cfg(feature = "stats")]
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+};
 
 use crate::{    
     tlas::{Tlas, TlasNode},
     tri::Tri,
     bvh::{Bvh, BvhInstance},
     aabb::Aabb,    
+    filter::QueryFilter,
 };
 use bevy::prelude::*;
 use bevy::render::camera::CameraProjection;
@@ -32,12 +38,175 @@ impl Default for Hit {
     }
 }
 
+/// Work done by traversals, see Ray::intersect_tlas_counted
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraversalStats {
+    pub rays: u64,
+    pub tlas_nodes: u64,
+    pub blas_nodes: u64,
+    pub aabb_tests: u64,
+    pub tri_tests: u64,
+    // rays moved into an instance's mesh space
+    pub instance_transforms: u64,
+}
+
+impl TraversalStats {
+    pub fn nodes(&self) -> u64 {
+        self.tlas_nodes + self.blas_nodes
+    }
+
+    /// Runs f, returning the tlas queries it made on this thread instead of adding each one to
+    /// the tlas totals, hand them over once with Tlas::add_query_stats
+    ///
+    /// Saves contending on the totals when many threads are querying, render_tiles does this per tile
+    #[cfg(feature = "stats")]
+    pub fn collect<T>(f: impl FnOnce() -> T) -> (T, TraversalStats) {
+        let outer = COLLECTED.with(|collected| collected.replace(Some(TraversalStats::default())));
+        let result = f();
+        let collected = COLLECTED.with(|collected| collected.replace(outer));
+        (result, collected.unwrap_or_default())
+    }
+
+    #[cfg(feature = "stats")]
+    fn to_array(self) -> [u64; 6] {
+        [
+            self.rays,
+            self.tlas_nodes,
+            self.blas_nodes,
+            self.aabb_tests,
+            self.tri_tests,
+            self.instance_transforms,
+        ]
+    }
+}
+
+impl std::ops::AddAssign for TraversalStats {
+    fn add_assign(&mut self, other: Self) {
+        self.rays += other.rays;
+        self.tlas_nodes += other.tlas_nodes;
+        self.blas_nodes += other.blas_nodes;
+        self.aabb_tests += other.aabb_tests;
+        self.tri_tests += other.tri_tests;
+        self.instance_transforms += other.instance_transforms;
+    }
+}
+
+// queries made inside TraversalStats::collect on this thread
+#[cfg(feature = "stats")]
+thread_local! {
+    static COLLECTED: Cell<Option<TraversalStats>> = const { Cell::new(None) };
+}
+
+// A tlas' query totals, in the same order as to_array, queries come from any thread
+#[cfg(feature = "stats")]
+#[derive(Default, Debug)]
+pub(crate) struct QueryTotals([AtomicU64; 6]);
+
+#[cfg(feature = "stats")]
+impl QueryTotals {
+    pub(crate) fn add(&self, stats: TraversalStats) {
+        for (total, count) in self.0.iter().zip(stats.to_array()) {
+            total.fetch_add(count, AtomicOrdering::Relaxed);
+        }
+    }
+
+    pub(crate) fn take(&self) -> TraversalStats {
+        let [rays, tlas_nodes, blas_nodes, aabb_tests, tri_tests, instance_transforms] =
+            [0, 1, 2, 3, 4, 5].map(|i| self.0[i].swap(0, AtomicOrdering::Relaxed));
+        TraversalStats {
+            rays,
+            tlas_nodes,
+            blas_nodes,
+            aabb_tests,
+            tri_tests,
+            instance_transforms,
+        }
+    }
+}
+
+// Lets the traversal count its work, the () counter compiles down to nothing
+trait TraversalCounter {
+    fn ray(&mut self) {}
+    fn tlas_node(&mut self) {}
+    fn blas_node(&mut self) {}
+    fn aabb_test(&mut self) {}
+    fn tri_test(&mut self) {}
+    fn instance_transform(&mut self) {}
+    // adds a finished query to the tlas totals, or the thread's collect
+    fn record(&self, _tlas: &Tlas) {}
+}
+
+impl TraversalCounter for () {}
+
+impl TraversalCounter for TraversalStats {
+    #[inline(always)]
+    fn ray(&mut self) {
+        self.rays += 1;
+    }
+
+    #[inline(always)]
+    fn tlas_node(&mut self) {
+        self.tlas_nodes += 1;
+    }
+
+    #[inline(always)]
+    fn blas_node(&mut self) {
+        self.blas_nodes += 1;
+    }
+
+    #[inline(always)]
+    fn aabb_test(&mut self) {
+        self.aabb_tests += 1;
+    }
+
+    #[inline(always)]
+    fn tri_test(&mut self) {
+        self.tri_tests += 1;
+    }
+
+    #[inline(always)]
+    fn instance_transform(&mut self) {
+        self.instance_transforms += 1;
+    }
+
+    #[cfg(feature = "stats")]
+    fn record(&self, tlas: &Tlas) {
+        let collecting = COLLECTED.with(|collected| match collected.get() {
+            Some(mut total) => {
+                total += *self;
+                collected.set(Some(total));
+                true
+            }
+            None => false,
+        });
+        if !collecting {
+            tlas.add_query_stats(*self);
+        }
+    }
+}
+
+// what plain queries count with, nothing unless the stats feature is on
+#[cfg(feature = "stats")]
+type QueryCounter = TraversalStats;
+#[cfg(not(feature = "stats"))]
+type QueryCounter = ();
+
+// unit without the stats feature
+#[allow(clippy::let_unit_value)]
+fn count_query<T>(tlas: &Tlas, query: impl FnOnce(&mut QueryCounter) -> T) -> T {
+    let mut counter = QueryCounter::default();
+    counter.ray();
+    let result = query(&mut counter);
+    counter.record(tlas);
+    result
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3, // Should be normalized
     pub direction_inv: Vec3,
-    pub distance: f32,
+    pub distance: f32, // max distance, hits past this are ignored
     pub hit: Option<Hit>,
 }
 
@@ -64,51 +233,69 @@ impl Ray {
         }
     }
 
-    // TODO: This is from bevy_mod_raycast, need to do more reading up on ndc
+    /// Ray through a window position, position is in logical pixels from the bottom left
     pub fn from_screenspace(
         cursor_pos_screen: Vec2,
         window: &Window,
-        projection: &PerspectiveProjection,
+        projection: &impl CameraProjection,
         camera_transform: &GlobalTransform,
     ) -> Self {
-        let camera_position = camera_transform.compute_matrix();
-        let screen_size = Vec2::from([window.width() as f32, window.height() as f32]);
-        let projection_matrix = projection.get_projection_matrix();
-
-        // Normalized device coordinate cursor position from (-1, -1, -1) to (1, 1, 1)
-        let cursor_ndc = (cursor_pos_screen / screen_size) * 2.0 - Vec2::from([1.0, 1.0]);
-        let cursor_pos_ndc_near: Vec3 = cursor_ndc.extend(-1.0);
-        let cursor_pos_ndc_far: Vec3 = cursor_ndc.extend(1.0);
-
-        // Use near and far ndc points to generate a ray in world space
-        // This method is more robust than using the location of the camera as the start of
-        // the ray, because ortho cameras have a focal point at infinity!
-        let ndc_to_world: Mat4 = camera_position * projection_matrix.inverse();
-        let cursor_pos_near: Vec3 = ndc_to_world.project_point3(cursor_pos_ndc_near);
-        let cursor_pos_far: Vec3 = ndc_to_world.project_point3(cursor_pos_ndc_far);
-        let ray_direction = cursor_pos_far - cursor_pos_near;
+        Self::from_viewport(
+            cursor_pos_screen,
+            Vec2::ZERO,
+            Vec2::new(window.width(), window.height()),
+            projection,
+            camera_transform,
+        )
+    }
 
-        Ray {
-            origin: cursor_pos_near,
-            direction: ray_direction,
-            direction_inv: ray_direction.recip(),
-            distance: 1e30,
-            hit: None,
-        }
+    /// Ray through a position inside a viewport, all in the same pixel space from the bottom left
+    ///
+    /// Use the image size as the viewport for cameras rendering to an image, or the sub rect
+    /// for cameras only covering part of a window
+    pub fn from_viewport(
+        position: Vec2,
+        viewport_origin: Vec2,
+        viewport_size: Vec2,
+        projection: &impl CameraProjection,
+        camera_transform: &GlobalTransform,
+    ) -> Self {
+        let ndc = ((position - viewport_origin) / viewport_size) * 2.0 - Vec2::ONE;
+        Self::from_ndc(ndc, &projection.get_projection_matrix(), camera_transform)
+    }
+
+    /// Ray through normalized device coordinates, -1 to 1 on both axes with y up
+    pub fn from_ndc(
+        ndc: Vec2,
+        projection_matrix: &Mat4,
+        camera_transform: &GlobalTransform,
+    ) -> Self {
+        let ndc_to_world = camera_transform.compute_matrix() * projection_matrix.inverse();
+        Self::from_ndc_to_world(ndc, &ndc_to_world)
+    }
+
+    /// Same as from_ndc, with the camera transform times inverse projection already computed
+    pub fn from_ndc_to_world(ndc: Vec2, ndc_to_world: &Mat4) -> Self {
+        // Bevy projections use reversed z, the near plane is at 1 and the far plane (or infinity
+        // for perspective) at 0, so use a point halfway for the direction.
+        // Starting from the near plane instead of the camera origin also works for
+        // orthographic projections, whose focal point is at infinity
+        let near = ndc_to_world.project_point3(ndc.extend(1.0));
+        let far = ndc_to_world.project_point3(ndc.extend(0.5));
+        Ray::new(near, (far - near).normalize())
     }
 
     // Moller Trumbore
     // https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
+    // returns distance and barycentric coordinates, without touching the current hit
     #[inline(always)]
-    pub fn intersect_triangle(&mut self, tri: &Tri, tri_index: usize, entity: Entity) {
-        #[cfg(feature = "trace")]
-        let _span = info_span!("intersect_triangle").entered();
+    pub fn triangle_hit(&self, tri: &Tri) -> Option<(f32, f32, f32)> {
         let edge1 = tri.vertex1 - tri.vertex0;
         let edge2 = tri.vertex2 - tri.vertex0;
         let h = self.direction.cross(edge2);
-        let a = edge1.dot(h);  
-        if a.abs() < 0.00001 { 
-            return;
+        let a = edge1.dot(h);
+        if a.abs() < 0.00001 {
+            return None;
         }
 
         // ray parallel to triangle
@@ -116,16 +303,27 @@ impl Ray {
         let s = self.origin - tri.vertex0;
         let u = f * s.dot(h);
         if !(0.0..=1.0).contains(&u) {
-            return;
+            return None;
         }
         let q = s.cross(edge1);
         let v = f * self.direction.dot(q);
         if v < 0.0 || u + v > 1.0 {
-            return;
+            return None;
         }
         let t = f * edge2.dot(q);
-        // TODO: The option part here feels sloppy
-        if t > 0.0001 {
+        if t > 0.0001 && t < self.distance {
+            Some((t, u, v))
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn intersect_triangle(&mut self, tri: &Tri, tri_index: usize, entity: Entity) {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_triangle").entered();
+        if let Some((t, u, v)) = self.triangle_hit(tri) {
+            // TODO: The option part here feels sloppy
             if let Some(hit) = self.hit {
                 if t < hit.distance {
                     self.hit = Some(Hit {
@@ -170,7 +368,7 @@ impl Ray {
         let t_hit = if let Some(hit) = self.hit {
             hit.distance
         } else {
-            1e30f32
+            self.distance
         };
 
         if tmax >= tmin && tmin < t_hit && tmax > 0.0 {
@@ -181,15 +379,61 @@ impl Ray {
     }
     
     pub fn intersect_bvh(&mut self, bvh: &Bvh, entity: Entity) {
+        self.intersect_bvh_with(bvh, entity, &|_| true);
+    }
+
+    /// Same as intersect_bvh, any_hit is called for each candidate closer than the current hit
+    /// and can return false to ignore it, like for alpha tested triangles
+    pub fn intersect_bvh_with(
+        &mut self,
+        bvh: &Bvh,
+        entity: Entity,
+        any_hit: &dyn Fn(&Hit) -> bool,
+    ) {
+        self.traverse_bvh(bvh, entity, any_hit, false, &mut ());
+    }
+
+    // generic over the callback so the unfiltered queries don't pay for a dyn call per hit,
+    // first_hit_only stops at any accepted hit for occlusion rays
+    fn traverse_bvh<F: Fn(&Hit) -> bool + ?Sized>(
+        &mut self,
+        bvh: &Bvh,
+        entity: Entity,
+        any_hit: &F,
+        first_hit_only: bool,
+        counter: &mut impl TraversalCounter,
+    ) {
         #[cfg(feature = "trace")]
         let _span = info_span!("intersect_bvh").entered();
+        if bvh.tris.is_empty() {
+            return;
+        }
         let mut node = &bvh.nodes[0];
         let mut stack = Vec::with_capacity(64);
         loop {
+            counter.blas_node();
             if node.is_leaf() {
                 for i in 0..node.tri_count {
                     let tri_index = bvh.triangle_indexs[(node.left_first + i) as usize];
-                    self.intersect_triangle(&bvh.tris[tri_index], tri_index, entity);
+                    counter.tri_test();
+                    if let Some((distance, u, v)) = self.triangle_hit(&bvh.tris[tri_index]) {
+                        let hit = Hit {
+                            distance,
+                            u,
+                            v,
+                            tri_index,
+                            entity,
+                        };
+                        // check the distance first so the callback only sees hits that matter
+                        #[allow(clippy::unnecessary_map_or)]
+                        let closer = self.hit.map_or(true, |h| distance < h.distance);
+                        if closer && any_hit(&hit) {
+                            self.hit = Some(hit);
+                            if first_hit_only {
+                                return;
+                            }
+                        }
+                    }
                 }
                 if stack.is_empty() {
                     break;
@@ -201,6 +445,8 @@ impl Ray {
             let mut child2 = &bvh.nodes[(node.left_first + 1) as usize];
             let mut dist1 = self.intersect_aabb(&child1.aabb);
             let mut dist2 = self.intersect_aabb(&child2.aabb);
+            counter.aabb_test();
+            counter.aabb_test();
             if dist1 > dist2 {
                 swap(&mut dist1, &mut dist2);
                 swap(&mut child1, &mut child2);
@@ -219,33 +465,205 @@ impl Ray {
         }
     }
 
+    /// Collects every hit along the ray, not just the closest, hits are not sorted
+    pub fn intersect_bvh_all(&self, bvh: &Bvh, entity: Entity, hits: &mut Vec<Hit>) {
+        self.intersect_bvh_all_with(bvh, entity, hits, &|_| true);
+    }
+
+    /// Same as intersect_bvh_all, only keeping hits any_hit accepts
+    pub fn intersect_bvh_all_with(
+        &self,
+        bvh: &Bvh,
+        entity: Entity,
+        hits: &mut Vec<Hit>,
+        any_hit: &dyn Fn(&Hit) -> bool,
+    ) {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_bvh_all").entered();
+        if bvh.tris.is_empty() {
+            return;
+        }
+        // dont let an existing hit cull any nodes
+        let ray = Ray { hit: None, ..*self };
+        let mut stack = Vec::with_capacity(64);
+        stack.push(&bvh.nodes[0]);
+        while let Some(node) = stack.pop() {
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    let tri_index = bvh.triangle_indexs[(node.left_first + i) as usize];
+                    if let Some((distance, u, v)) = ray.triangle_hit(&bvh.tris[tri_index]) {
+                        let hit = Hit {
+                            distance,
+                            u,
+                            v,
+                            tri_index,
+                            entity,
+                        };
+                        if any_hit(&hit) {
+                            hits.push(hit);
+                        }
+                    }
+                }
+                continue;
+            }
+            for child in [node.left_first, node.left_first + 1] {
+                let child = &bvh.nodes[child as usize];
+                if ray.intersect_aabb(&child.aabb) != 1e30f32 {
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
+    /// Collects every hit in the tlas along the ray, hits are not sorted
+    pub fn intersect_tlas_all(&self, tlas: &Tlas, filter: &QueryFilter, hits: &mut Vec<Hit>) {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_tlas_all").entered();
+        if tlas.tlas_nodes.is_empty() || tlas.blas.is_empty() {
+            return;
+        }
+        let ray = Ray { hit: None, ..*self };
+        let mut stack = Vec::<&TlasNode>::with_capacity(64);
+        stack.push(&tlas.tlas_nodes[0]);
+        while let Some(node) = stack.pop() {
+            if node.is_leaf() {
+                let instance = &tlas.blas[node.blas as usize];
+                if filter.accepts(instance) {
+                    let mut local_ray = ray;
+                    local_ray.origin = instance.inv_trans.transform_point3(ray.origin);
+                    local_ray.direction = instance.inv_trans.transform_vector3(ray.direction);
+                    local_ray.direction_inv = local_ray.direction.recip();
+                    local_ray.intersect_bvh_all_with(
+                        &tlas.bvhs[instance.bvh_index],
+                        instance.entity,
+                        hits,
+                        &|hit| filter.accepts_hit(hit),
+                    );
+                }
+                continue;
+            }
+            for child in [node.left_right & 0xffff, node.left_right >> 16] {
+                let child = &tlas.tlas_nodes[child as usize];
+                if ray.intersect_aabb(&child.aabb) != 1e30f32 {
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
     pub fn intersect_bvh_instance(&mut self, bvh_instance: &BvhInstance, bvhs: &[Bvh]) {
+        self.intersect_bvh_instance_with(bvh_instance, bvhs, &|_| true);
+    }
+
+    /// Same as intersect_bvh_instance, see intersect_bvh_with for any_hit
+    pub fn intersect_bvh_instance_with(
+        &mut self,
+        bvh_instance: &BvhInstance,
+        bvhs: &[Bvh],
+        any_hit: &dyn Fn(&Hit) -> bool,
+    ) {
+        self.traverse_bvh_instance(bvh_instance, bvhs, any_hit, false, &mut ());
+    }
+
+    fn traverse_bvh_instance<F: Fn(&Hit) -> bool + ?Sized>(
+        &mut self,
+        bvh_instance: &BvhInstance,
+        bvhs: &[Bvh],
+        any_hit: &F,
+        first_hit_only: bool,
+        counter: &mut impl TraversalCounter,
+    ) {
         #[cfg(feature = "trace")]
         let _span = info_span!("intersect_bvh_instance").entered();
         let bvh = &bvhs[bvh_instance.bvh_index];
         // backup ray and transform original
         let mut backup_ray = *self;
 
+        counter.instance_transform();
         self.origin = bvh_instance.inv_trans.transform_point3(self.origin);
         self.direction = bvh_instance.inv_trans.transform_vector3(self.direction);
         self.direction_inv = self.direction.recip();
-        self.intersect_bvh(bvh, bvh_instance.entity);
+        self.traverse_bvh(bvh, bvh_instance.entity, any_hit, first_hit_only, counter);
 
         // restore ray origin and direction
         backup_ray.hit = self.hit;
         *self = backup_ray;
     }
 
-    pub fn intersect_tlas(&mut self, tlas: &Tlas) -> Option<Hit> {        
-        if tlas.tlas_nodes.is_empty() {
+    pub fn intersect_tlas(&mut self, tlas: &Tlas) -> Option<Hit> {
+        self.intersect_tlas_filtered(tlas, &QueryFilter::default())
+    }
+
+    pub fn intersect_tlas_filtered(&mut self, tlas: &Tlas, filter: &QueryFilter) -> Option<Hit> {
+        count_query(tlas, |counter| {
+            self.traverse_tlas(tlas, filter, false, counter)
+        })
+    }
+
+    /// Tests for any hit within the ray's distance, stopping at the first one found rather
+    /// than the closest, for shadow and line of sight rays
+    ///
+    /// Any existing hit is cleared, the one found is left in self.hit
+    pub fn occluded_tlas(&mut self, tlas: &Tlas, filter: &QueryFilter) -> bool {
+        self.hit = None;
+        count_query(tlas, |counter| {
+            self.traverse_tlas(tlas, filter, true, counter)
+        })
+        .is_some()
+    }
+
+    /// Same as intersect_tlas_filtered, adding the work done to stats
+    pub fn intersect_tlas_counted(
+        &mut self,
+        tlas: &Tlas,
+        filter: &QueryFilter,
+        stats: &mut TraversalStats,
+    ) -> Option<Hit> {
+        let mut query = TraversalStats::default();
+        query.ray();
+        let hit = self.traverse_tlas(tlas, filter, false, &mut query);
+        query.record(tlas);
+        *stats += query;
+        hit
+    }
+
+    fn traverse_tlas(
+        &mut self,
+        tlas: &Tlas,
+        filter: &QueryFilter,
+        first_hit_only: bool,
+        counter: &mut impl TraversalCounter,
+    ) -> Option<Hit> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_tlas").entered();
+        if tlas.tlas_nodes.is_empty() || tlas.blas.is_empty() {
            return None
         }        
         let mut stack = Vec::<&TlasNode>::with_capacity(64);
         let mut node = &tlas.tlas_nodes[0];
         loop {
+            counter.tlas_node();
             if node.is_leaf() {
-                self.intersect_bvh_instance(&tlas.blas[node.blas as usize], &tlas.bvhs);
-                if stack.is_empty() {
+                let instance = &tlas.blas[node.blas as usize];
+                if filter.accepts(instance) {
+                    match filter.any_hit {
+                        Some(any_hit) => self.traverse_bvh_instance(
+                            instance,
+                            &tlas.bvhs,
+                            any_hit,
+                            first_hit_only,
+                            counter,
+                        ),
+                        None => self.traverse_bvh_instance(
+                            instance,
+                            &tlas.bvhs,
+                            &|_: &Hit| true,
+                            first_hit_only,
+                            counter,
+                        ),
+                    }
+                }
+                if stack.is_empty() || (first_hit_only && self.hit.is_some()) {
                     break;
                 } else {
                     node = stack.pop().unwrap();
@@ -256,6 +674,8 @@ impl Ray {
             let mut child2 = &tlas.tlas_nodes[(node.left_right >> 16) as usize];
             let mut dist1 = self.intersect_aabb(&child1.aabb);
             let mut dist2 = self.intersect_aabb(&child2.aabb);
+            counter.aabb_test();
+            counter.aabb_test();
             if dist1 > dist2 {
                 swap(&mut dist1, &mut dist2);
                 swap(&mut child1, &mut child2);
@@ -276,3 +696,117 @@ impl Ray {
         self.hit
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_8;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 1e-4), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn perspective_rays() {
+        let mut projection = PerspectiveProjection::default();
+        projection.update(200.0, 100.0);
+        let trans = GlobalTransform::from_xyz(1.0, 2.0, 3.0);
+
+        let center = Ray::from_ndc(Vec2::ZERO, &projection.get_projection_matrix(), &trans);
+        assert_near(center.origin, Vec3::new(1.0, 2.0, 3.0 - projection.near));
+        assert_near(center.direction, -Vec3::Z);
+
+        // the corner is at the edge of the vertical fov, stretched by the aspect ratio
+        let corner = Ray::from_ndc(Vec2::ONE, &projection.get_projection_matrix(), &trans);
+        let tan = FRAC_PI_8.tan();
+        let expected = Vec3::new(tan * 2.0, tan, -1.0);
+        assert_near(corner.direction, expected.normalize());
+        assert_near(
+            corner.origin,
+            trans.translation + expected * projection.near,
+        );
+    }
+
+    #[test]
+    fn viewport_offset() {
+        // a 200x100 viewport at 100,50 in a larger window
+        let mut projection = PerspectiveProjection::default();
+        projection.update(200.0, 100.0);
+        let trans = GlobalTransform::default();
+        let origin = Vec2::new(100.0, 50.0);
+        let size = Vec2::new(200.0, 100.0);
+
+        let center = Ray::from_viewport(origin + size / 2.0, origin, size, &projection, &trans);
+        assert_near(center.direction, -Vec3::Z);
+
+        let corner = Ray::from_viewport(origin + size, origin, size, &projection, &trans);
+        let expected = Ray::from_ndc(Vec2::ONE, &projection.get_projection_matrix(), &trans);
+        assert_near(corner.origin, expected.origin);
+        assert_near(corner.direction, expected.direction);
+    }
+
+    #[test]
+    fn orthographic_rays() {
+        let mut projection = OrthographicProjection {
+            near: 1.0,
+            ..Default::default()
+        };
+        projection.update(200.0, 100.0);
+        let trans = GlobalTransform::from_xyz(0.0, 0.0, 10.0);
+        let matrix = projection.get_projection_matrix();
+
+        // parallel rays, each starting on the near plane
+        for (ndc, offset) in [
+            (Vec2::ZERO, Vec2::ZERO),
+            (Vec2::ONE, Vec2::new(100.0, 50.0)),
+            (Vec2::new(-1.0, 0.5), Vec2::new(-100.0, 25.0)),
+        ] {
+            let ray = Ray::from_ndc(ndc, &matrix, &trans);
+            assert_near(ray.origin, offset.extend(9.0));
+            assert_near(ray.direction, -Vec3::Z);
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn query_stats_per_tlas() {
+        let cube_tlas = || {
+            let (tris, tri_data) = crate::parse_mesh(&Mesh::from(shape::Cube { size: 2.0 }));
+            let mut tlas = Tlas::default();
+            let bvh_index = tlas.add_bvh(Bvh::new_with_data(tris, tri_data));
+            let mut instance = BvhInstance::new(Entity::from_raw(0), bvh_index);
+            instance.update(&GlobalTransform::identity(), &tlas.bvhs[bvh_index].nodes[0]);
+            tlas.add_instance(instance);
+            tlas.build();
+            tlas
+        };
+        let tlas = cube_tlas();
+        let other = cube_tlas();
+        let intersect = |tlas: &Tlas| Ray::new(Vec3::Z * 5.0, -Vec3::Z).intersect_tlas(tlas);
+
+        let mut counted = TraversalStats::default();
+        Ray::new(Vec3::Z * 5.0, -Vec3::Z).intersect_tlas_counted(
+            &tlas,
+            &QueryFilter::default(),
+            &mut counted,
+        );
+        assert_eq!(counted.rays, 1);
+        assert!(counted.tri_tests > 0 && counted.instance_transforms == 1);
+        intersect(&tlas);
+        let mut totals = counted;
+        totals += counted;
+        assert_eq!(tlas.take_query_stats(), totals);
+        assert_eq!(tlas.take_query_stats(), TraversalStats::default());
+        assert_eq!(other.take_query_stats(), TraversalStats::default());
+
+        // collected queries stay out of the totals until handed over
+        let (_, collected) = TraversalStats::collect(|| {
+            intersect(&tlas);
+            intersect(&other);
+        });
+        assert_eq!(collected, totals);
+        assert_eq!(tlas.take_query_stats(), TraversalStats::default());
+        tlas.add_query_stats(collected);
+        assert_eq!(tlas.take_query_stats(), totals);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    filter::QueryFilter,
+    ray::{Hit, Ray},
+    tlas::Tlas,
+};
+use bevy::{ecs::system::SystemParam, prelude::*, render::camera::RenderTarget};
+
+/// World space result of a raycast
+#[derive(Debug, Clone, Copy)]
+pub struct RaycastHit {
+    pub entity: Entity,
+    pub position: Vec3,
+    // geometric normal, facing back towards the ray
+    pub normal: Vec3,
+    pub distance: f32,
+    pub tri_index: usize,
+}
+
+impl RaycastHit {
+    fn new(ray: &Ray, hit: &Hit, tlas: &Tlas) -> Self {
+        let instance = tlas
+            .get_instance(hit.entity)
+            .expect("Hit entity not in tlas");
+        let tri = &tlas.bvhs[instance.bvh_index].tris[hit.tri_index];
+        let local_normal = (tri.vertex1 - tri.vertex0).cross(tri.vertex2 - tri.vertex0);
+        let mut normal = instance
+            .inv_trans
+            .transpose()
+            .transform_vector3(local_normal)
+            .normalize();
+        if normal.dot(ray.direction) > 0.0 {
+            normal = -normal;
+        }
+
+        Self {
+            entity: hit.entity,
+            position: ray.origin + ray.direction * hit.distance,
+            normal,
+            distance: hit.distance,
+            tri_index: hit.tri_index,
+        }
+    }
+}
+
+/// Raycasts against the tlas from any system, without touching tlas internals
+///
+/// Works headless too, the cursor methods just return None without a WindowPlugin
+#[derive(SystemParam)]
+pub struct BvhRaycast<'w, 's> {
+    tlas: Res<'w, Tlas>,
+    windows: Option<Res<'w, Windows>>,
+    cameras: Query<'w, 's, (&'static Camera, &'static GlobalTransform)>,
+}
+
+impl<'w, 's> BvhRaycast<'w, 's> {
+    /// Closest hit along the direction, within max_distance
+    pub fn cast_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        filter: &QueryFilter,
+    ) -> Option<RaycastHit> {
+        let mut ray = Self::ray(origin, direction, max_distance);
+        ray.intersect_tlas_filtered(&self.tlas, filter)
+            .map(|hit| RaycastHit::new(&ray, &hit, &self.tlas))
+    }
+
+    /// Tests if anything is hit within max_distance, useful for line of sight
+    pub fn any_hit(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        filter: &QueryFilter,
+    ) -> bool {
+        let mut ray = Self::ray(origin, direction, max_distance);
+        ray.occluded_tlas(&self.tlas, filter)
+    }
+
+    /// Every hit along the direction within max_distance, sorted nearest first
+    pub fn cast_all(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        filter: &QueryFilter,
+    ) -> Vec<RaycastHit> {
+        let ray = Self::ray(origin, direction, max_distance);
+        let mut hits = Vec::new();
+        ray.intersect_tlas_all(&self.tlas, filter, &mut hits);
+        hits.sort_unstable_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits.iter()
+            .map(|hit| RaycastHit::new(&ray, hit, &self.tlas))
+            .collect()
+    }
+
+    /// Casts from the camera through the cursor, None if the cursor isn't over the camera's window
+    pub fn from_cursor(&self, camera: Entity, filter: &QueryFilter) -> Option<RaycastHit> {
+        let mut ray = self.cursor_ray(camera)?;
+        ray.intersect_tlas_filtered(&self.tlas, filter)
+            .map(|hit| RaycastHit::new(&ray, &hit, &self.tlas))
+    }
+
+    /// World space ray from the camera through the cursor
+    pub fn cursor_ray(&self, camera: Entity) -> Option<Ray> {
+        let (camera, trans) = self.cameras.get(camera).ok()?;
+        let window = match &camera.target {
+            RenderTarget::Window(id) => self.windows.as_ref()?.get(*id)?,
+            RenderTarget::Image(_) => return None,
+        };
+        let cursor = window.cursor_position()?;
+        let ndc = (cursor / Vec2::new(window.width(), window.height())) * 2.0 - Vec2::ONE;
+        Some(Ray::from_ndc(ndc, &camera.projection_matrix, trans))
+    }
+
+    fn ray(origin: Vec3, direction: Vec3, max_distance: f32) -> Ray {
+        let mut ray = Ray::new(origin, direction.normalize());
+        ray.distance = max_distance;
+        ray
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_mesh, Bvh, BvhInstance};
+
+    #[derive(Default)]
+    struct Results {
+        hit: Option<RaycastHit>,
+        cursor_ray: Option<Ray>,
+    }
+
+    fn cast(
+        raycast: BvhRaycast,
+        cameras: Query<Entity, With<Camera>>,
+        mut results: ResMut<Results>,
+    ) {
+        let filter = QueryFilter::default();
+        results.hit = raycast.cast_ray(Vec3::Z * 5.0, -Vec3::Z, 10.0, &filter);
+        results.cursor_ray = raycast.cursor_ray(cameras.single());
+    }
+
+    #[test]
+    fn works_without_windows() {
+        let (tris, tri_data) = parse_mesh(&Mesh::from(shape::Cube { size: 2.0 }));
+        let mut tlas = Tlas::default();
+        let bvh_index = tlas.add_bvh(Bvh::new_with_data(tris, tri_data));
+        let mut instance = BvhInstance::new(Entity::from_raw(0), bvh_index);
+        instance.update(&GlobalTransform::identity(), &tlas.bvhs[bvh_index].nodes[0]);
+        tlas.add_instance(instance);
+        tlas.build();
+
+        let mut world = World::new();
+        world.insert_resource(tlas);
+        world.init_resource::<Results>();
+        world
+            .spawn()
+            .insert(Camera::default())
+            .insert(GlobalTransform::identity());
+        let mut stage = SystemStage::single(cast);
+        stage.run(&mut world);
+
+        let results = world.resource::<Results>();
+        let hit = results.hit.expect("cast_ray needs no window");
+        assert!((hit.distance - 4.0).abs() < 0.0001);
+        assert!(results.cursor_ray.is_none());
+    }
+}
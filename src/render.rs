@@ -0,0 +1,874 @@
+#[cfg(feature = "camera")]
+use crate::camera::{BvhCamera, Tile};
+use crate::{
+    filter::QueryFilter,
+    ray::{Hit, Ray, TraversalStats},
+    tlas::Tlas,
+};
+use bevy::{
+    asset::HandleId, math::vec3, prelude::*, render::render_resource::TextureFormat, utils::HashMap,
+};
+use rand::Rng;
+#[cfg(feature = "camera")]
+use rand::SeedableRng;
+#[cfg(feature = "camera")]
+use rand_chacha::ChaChaRng;
+#[cfg(feature = "camera")]
+use rayon::prelude::*;
+use std::{f32::consts::PI, sync::Arc};
+
+// Same fixed exposure bevy uses for directional lights, see bevy_pbr light.rs
+const APERTURE: f32 = 4.0;
+const SHUTTER_SPEED: f32 = 1.0 / 250.0;
+const SENSITIVITY: f32 = 100.0;
+
+// offset along the normal for secondary rays so they don't hit the surface they start on
+const SURFACE_BIAS: f32 = 0.001;
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Colors pixels by the barycentric coordinates of the hit, no scene data needed
+    #[default]
+    Barycentric,
+    /// Direct lighting from the scene lights using the entities StandardMaterial
+    Shaded,
+    /// Progressive path tracing, accumulates samples while the camera and scene are still
+    PathTrace,
+    /// Colors pixels by the nodes visited and triangles tested, blue to red up to heatmap_max
+    Heatmap,
+}
+
+/// How samples inside a pixel are weighted when averaged
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum PixelFilter {
+    /// Samples cover just the pixel and count equally
+    #[default]
+    Box,
+    /// Samples spread out to radius pixels from the center, falling off with distance
+    Gaussian { radius: f32 },
+}
+
+impl PixelFilter {
+    /// Half width of the sample footprint in pixels
+    pub fn radius(&self) -> f32 {
+        match *self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Gaussian { radius } => radius,
+        }
+    }
+
+    pub fn weight(&self, offset: Vec2) -> f32 {
+        match *self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Gaussian { radius } => {
+                // shifted so the weight reaches zero at the radius, see pbrt's GaussianFilter
+                const ALPHA: f32 = 2.0;
+                let edge = (-ALPHA * radius * radius).exp();
+                let gaussian = |d: f32| ((-ALPHA * d * d).exp() - edge).max(0.0);
+                gaussian(offset.x) * gaussian(offset.y)
+            }
+        }
+    }
+
+    /// Offset from the pixel center for sample index of count, jittered within its stratum
+    pub fn sample(&self, index: u32, count: u32, rng: &mut impl Rng) -> Vec2 {
+        // rows is the largest factor of count up to its square root so every cell gets a
+        // sample, prime counts end up as strips across the pixel
+        let count = count.max(1);
+        #[allow(clippy::manual_is_multiple_of)]
+        let rows = (1..=(count as f32).sqrt() as u32)
+            .rev()
+            .find(|rows| count % rows == 0)
+            .unwrap_or(1);
+        let columns = count / rows;
+        let cell = Vec2::new((index % columns) as f32, (index / columns) as f32);
+        let jitter = Vec2::new(rng.gen::<f32>(), rng.gen::<f32>());
+        let unit = (cell + jitter) / Vec2::new(columns as f32, rows as f32);
+        (unit * 2.0 - Vec2::ONE) * self.radius()
+    }
+}
+
+/// The parts of a StandardMaterial the cpu renderer understands, colors are linear
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SurfaceMaterial {
+    pub base_color: Vec3,
+    pub alpha: f32,
+    // multiplies base_color and alpha, looked up in the scene's textures
+    pub base_color_texture: Option<HandleId>,
+    pub emissive: Vec3,
+    pub perceptual_roughness: f32,
+    pub metallic: f32,
+    pub reflectance: f32,
+    pub unlit: bool,
+    // from AlphaMode::Mask, hits with less alpha are see through
+    pub alpha_cutoff: Option<f32>,
+}
+
+impl Default for SurfaceMaterial {
+    fn default() -> Self {
+        Self::from(&StandardMaterial::default())
+    }
+}
+
+impl From<&StandardMaterial> for SurfaceMaterial {
+    fn from(material: &StandardMaterial) -> Self {
+        Self {
+            base_color: linear_rgb(material.base_color),
+            alpha: material.base_color.a(),
+            base_color_texture: material.base_color_texture.as_ref().map(|handle| handle.id),
+            emissive: linear_rgb(material.emissive),
+            perceptual_roughness: material.perceptual_roughness,
+            metallic: material.metallic,
+            reflectance: material.reflectance,
+            unlit: material.unlit,
+            alpha_cutoff: match material.alpha_mode {
+                AlphaMode::Mask(cutoff) => Some(cutoff),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Rgba8 copy of an Image the cpu renderer can sample from any thread
+#[derive(Debug, PartialEq, Eq)]
+pub struct CpuTexture {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    pub srgb: bool,
+}
+
+impl CpuTexture {
+    /// None for formats other than Rgba8Unorm and Rgba8UnormSrgb
+    pub fn from_image(image: &Image) -> Option<Self> {
+        let srgb = match image.texture_descriptor.format {
+            TextureFormat::Rgba8UnormSrgb => true,
+            TextureFormat::Rgba8Unorm => false,
+            _ => return None,
+        };
+        let size = image.texture_descriptor.size;
+        Some(Self {
+            width: size.width,
+            height: size.height,
+            data: image.data.clone(),
+            srgb,
+        })
+    }
+
+    /// Bilinear filtered with repeat wrapping, rgb is linear
+    pub fn sample(&self, uv: Vec2) -> Vec4 {
+        if self.width == 0 || self.height == 0 {
+            return Vec4::ONE;
+        }
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = uv.y * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |dx: f32, dy: f32| self.texel(x0 + dx, y0 + dy);
+        let top = texel(0.0, 0.0).lerp(texel(1.0, 0.0), fx);
+        let bottom = texel(0.0, 1.0).lerp(texel(1.0, 1.0), fx);
+        top.lerp(bottom, fy)
+    }
+
+    fn texel(&self, x: f32, y: f32) -> Vec4 {
+        let x = (x as i64).rem_euclid(self.width as i64) as usize;
+        let y = (y as i64).rem_euclid(self.height as i64) as usize;
+        let i = (y * self.width as usize + x) * 4;
+        let [r, g, b, a] = [0, 1, 2, 3].map(|c| self.data[i + c] as f32 / 255.0);
+        if self.srgb {
+            // decode before filtering, blending encoded values darkens edges
+            Vec4::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a)
+        } else {
+            Vec4::new(r, g, b, a)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SceneLight {
+    Directional {
+        // normalized, pointing towards the light
+        direction: Vec3,
+        // linear color premultiplied by illuminance and exposure
+        color: Vec3,
+    },
+    Point {
+        position: Vec3,
+        // linear color premultiplied by luminous intensity
+        color: Vec3,
+        range: f32,
+    },
+}
+
+impl SceneLight {
+    pub fn directional(light: &DirectionalLight, trans: &GlobalTransform) -> Self {
+        let ev100 = (APERTURE * APERTURE / SHUTTER_SPEED).log2() - (SENSITIVITY / 100.0).log2();
+        let exposure = 1.0 / (2.0f32.powf(ev100) * 1.2);
+        SceneLight::Directional {
+            direction: -trans.forward(),
+            color: linear_rgb(light.color) * light.illuminance * exposure,
+        }
+    }
+
+    pub fn point(light: &PointLight, trans: &GlobalTransform) -> Self {
+        SceneLight::Point {
+            position: trans.translation,
+            // luminous power in lumens to luminous intensity
+            color: linear_rgb(light.color) * light.intensity / (4.0 * PI),
+            range: light.range,
+        }
+    }
+}
+
+/// Scene data used to shade hits, gathered from the world each frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderScene {
+    pub materials: HashMap<Entity, SurfaceMaterial>,
+    // shared so unchanged textures aren't copied when the scene is gathered again
+    pub textures: HashMap<HandleId, Arc<CpuTexture>>,
+    pub lights: Vec<SceneLight>,
+    // linear color premultiplied by brightness
+    pub ambient: Vec3,
+    // linear color for rays that miss
+    pub background: Vec3,
+}
+
+impl Default for RenderScene {
+    fn default() -> Self {
+        Self {
+            materials: Default::default(),
+            textures: Default::default(),
+            lights: Default::default(),
+            ambient: linear_rgb(AmbientLight::default().color) * AmbientLight::default().brightness,
+            background: Vec3::ZERO,
+        }
+    }
+}
+
+impl RenderScene {
+    /// Linear radiance leaving the hit back along the ray
+    ///
+    /// Shadow and occlusion rays follow the camera's settings, and are added to secondary_rays
+    #[cfg(feature = "camera")]
+    pub fn shade(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        tlas: &Tlas,
+        camera: &BvhCamera,
+        rng: &mut impl Rng,
+        secondary_rays: &mut usize,
+    ) -> Vec3 {
+        let material = self.material_at(hit, tlas);
+        if material.unlit {
+            return material.base_color;
+        }
+        let surface = Surface::new(ray, hit, tlas, &material);
+        let color = self.direct_light(&surface, tlas, camera.shadows, secondary_rays);
+
+        // flat ambient, same approximation bevy uses
+        let diffuse_ambient = env_brdf_approx(surface.diffuse_color, 1.0, surface.n_dot_v);
+        let specular_ambient =
+            env_brdf_approx(surface.f0, surface.perceptual_roughness, surface.n_dot_v);
+        let mut ambient = (diffuse_ambient + specular_ambient) * self.ambient;
+        if camera.ao_samples > 0 && self.ambient != Vec3::ZERO {
+            *secondary_rays += camera.ao_samples as usize;
+            ambient *= self.ambient_occlusion(
+                tlas,
+                surface.biased_position(),
+                surface.normal,
+                camera.ao_samples,
+                camera.ao_radius,
+                rng,
+            );
+        }
+        color + ambient + material.emissive
+    }
+
+    /// One path traced sample of the linear radiance arriving along the ray
+    ///
+    /// Lights are sampled directly at every bounce, ambient light is treated as a uniform sky
+    pub fn trace_path(
+        &self,
+        ray: &Ray,
+        tlas: &Tlas,
+        max_bounces: u32,
+        rng: &mut impl Rng,
+        secondary_rays: &mut usize,
+    ) -> Vec3 {
+        let mut ray = *ray;
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        for bounce in 0..=max_bounces {
+            if bounce > 0 {
+                *secondary_rays += 1;
+            }
+            let hit = match self.intersect(&mut ray, tlas) {
+                Some(hit) => hit,
+                None => {
+                    let sky = if bounce == 0 {
+                        self.background
+                    } else {
+                        self.ambient
+                    };
+                    radiance += throughput * sky;
+                    break;
+                }
+            };
+
+            let material = self.material_at(&hit, tlas);
+            radiance += throughput * material.emissive;
+            if material.unlit {
+                radiance += throughput * material.base_color;
+                break;
+            }
+            let surface = Surface::new(&ray, &hit, tlas, &material);
+            radiance += throughput * self.direct_light(&surface, tlas, true, secondary_rays);
+            if bounce == max_bounces {
+                break;
+            }
+
+            let (direction, weight) = match surface.sample(rng) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput *= weight;
+
+            // russian roulette, keeps long paths unbiased without tracing all of them
+            if bounce >= 3 {
+                let survive = throughput.max_element().min(0.95);
+                if rng.gen::<f32>() >= survive {
+                    break;
+                }
+                throughput /= survive;
+            }
+            ray = Ray::new(surface.biased_position(), direction);
+        }
+        radiance
+    }
+
+    /// Closest hit along the ray, passing through alpha masked texels
+    pub fn intersect(&self, ray: &mut Ray, tlas: &Tlas) -> Option<Hit> {
+        let any_hit = |hit: &Hit| self.accepts_hit(hit, tlas);
+        ray.intersect_tlas_filtered(tlas, &QueryFilter::default().with_any_hit(&any_hit))
+    }
+
+    /// Same as intersect, adding the work done to stats
+    pub fn intersect_counted(
+        &self,
+        ray: &mut Ray,
+        tlas: &Tlas,
+        stats: &mut TraversalStats,
+    ) -> Option<Hit> {
+        let any_hit = |hit: &Hit| self.accepts_hit(hit, tlas);
+        ray.intersect_tlas_counted(tlas, &QueryFilter::default().with_any_hit(&any_hit), stats)
+    }
+
+    /// Material at the hit with its base color texture applied
+    pub fn material_at(&self, hit: &Hit, tlas: &Tlas) -> SurfaceMaterial {
+        let mut material = self.materials.get(&hit.entity).copied().unwrap_or_default();
+        if let Some(texture) = material
+            .base_color_texture
+            .and_then(|id| self.textures.get(&id))
+        {
+            let texel = texture.sample(tlas.hit_uv(hit));
+            material.base_color *= texel.truncate();
+            material.alpha *= texel.w;
+        }
+        material
+    }
+
+    /// Any hit test for QueryFilter::with_any_hit, false for texels cut by an alpha mask
+    pub fn accepts_hit(&self, hit: &Hit, tlas: &Tlas) -> bool {
+        match self.materials.get(&hit.entity).and_then(|m| m.alpha_cutoff) {
+            Some(cutoff) => self.material_at(hit, tlas).alpha >= cutoff,
+            None => true,
+        }
+    }
+
+    // any hit will do, so the traversal stops at the first one
+    fn is_occluded(&self, tlas: &Tlas, origin: Vec3, direction: Vec3, distance: f32) -> bool {
+        let mut ray = Ray::new(origin, direction);
+        ray.distance = distance;
+        let any_hit = |hit: &Hit| self.accepts_hit(hit, tlas);
+        ray.occluded_tlas(tlas, &QueryFilter::default().with_any_hit(&any_hit))
+    }
+
+    /// Fraction of cosine weighted hemisphere rays that escape within radius, 1.0 is fully open
+    pub fn ambient_occlusion(
+        &self,
+        tlas: &Tlas,
+        origin: Vec3,
+        normal: Vec3,
+        samples: u32,
+        radius: f32,
+        rng: &mut impl Rng,
+    ) -> f32 {
+        let mut open = 0;
+        for _ in 0..samples {
+            let direction = cosine_hemisphere(normal, rng);
+            if !self.is_occluded(tlas, origin, direction, radius) {
+                open += 1;
+            }
+        }
+        open as f32 / samples as f32
+    }
+
+    // lights are points or directions, so they can only be reached by sampling them here
+    fn direct_light(
+        &self,
+        surface: &Surface,
+        tlas: &Tlas,
+        shadows: bool,
+        secondary_rays: &mut usize,
+    ) -> Vec3 {
+        let mut color = Vec3::ZERO;
+        for light in &self.lights {
+            let (light_dir, radiance, light_distance) = match *light {
+                SceneLight::Directional { direction, color } => (direction, color, f32::MAX),
+                SceneLight::Point {
+                    position: light_position,
+                    color,
+                    range,
+                } => {
+                    let to_light = light_position - surface.position;
+                    let distance_squared = to_light.length_squared();
+                    let attenuation = distance_attenuation(distance_squared, range);
+                    (
+                        to_light.normalize(),
+                        color * attenuation,
+                        distance_squared.sqrt(),
+                    )
+                }
+            };
+            if radiance == Vec3::ZERO || surface.normal.dot(light_dir) <= 0.0 {
+                continue;
+            }
+            if shadows {
+                *secondary_rays += 1;
+                if self.is_occluded(tlas, surface.biased_position(), light_dir, light_distance) {
+                    continue;
+                }
+            }
+            color += surface.brdf(light_dir) * radiance;
+        }
+        color
+    }
+}
+
+// Material params resolved at a hit
+struct Surface {
+    position: Vec3,
+    normal: Vec3,
+    view: Vec3,
+    n_dot_v: f32,
+    // only read by shade, which needs the camera feature
+    #[cfg_attr(not(feature = "camera"), allow(dead_code))]
+    perceptual_roughness: f32,
+    roughness: f32,
+    f0: Vec3,
+    diffuse_color: Vec3,
+}
+
+impl Surface {
+    fn new(ray: &Ray, hit: &Hit, tlas: &Tlas, material: &SurfaceMaterial) -> Self {
+        let view = -ray.direction;
+        let normal = tlas.hit_normal(hit, view);
+        let perceptual_roughness = material.perceptual_roughness.clamp(0.089, 1.0);
+        Self {
+            position: ray.origin + ray.direction * hit.distance,
+            normal,
+            view,
+            n_dot_v: normal.dot(view).max(0.0001),
+            perceptual_roughness,
+            roughness: perceptual_roughness * perceptual_roughness,
+            f0: Vec3::splat(0.16 * material.reflectance * material.reflectance)
+                * (1.0 - material.metallic)
+                + material.base_color * material.metallic,
+            diffuse_color: material.base_color * (1.0 - material.metallic),
+        }
+    }
+
+    fn biased_position(&self) -> Vec3 {
+        self.position + self.normal * SURFACE_BIAS
+    }
+
+    fn brdf(&self, light_dir: Vec3) -> Vec3 {
+        brdf(
+            self.normal,
+            self.view,
+            light_dir,
+            self.n_dot_v,
+            self.roughness,
+            self.f0,
+            self.diffuse_color,
+        )
+    }
+
+    // Picks the diffuse or specular lobe, returns the new direction and brdf * cos / pdf
+    fn sample(&self, rng: &mut impl Rng) -> Option<(Vec3, Vec3)> {
+        let specular_weight = fresnel(self.f0, self.n_dot_v).max_element();
+        let diffuse_weight = self.diffuse_color.max_element() * (1.0 - specular_weight);
+        let specular_chance = specular_weight / (specular_weight + diffuse_weight).max(0.0001);
+
+        if rng.gen::<f32>() < specular_chance {
+            // sample the ggx distribution for a half vector and reflect the view around it
+            let r1 = rng.gen::<f32>();
+            let r2 = rng.gen::<f32>();
+            let a2 = self.roughness * self.roughness;
+            let cos_theta = ((1.0 - r1) / (1.0 + (a2 - 1.0) * r1)).sqrt();
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let phi = 2.0 * PI * r2;
+            let (tangent, bitangent) = self.normal.any_orthonormal_pair();
+            let half = (tangent * sin_theta * phi.cos()
+                + bitangent * sin_theta * phi.sin()
+                + self.normal * cos_theta)
+                .normalize();
+            let direction = (2.0 * self.view.dot(half) * half - self.view).normalize();
+
+            let n_dot_l = self.normal.dot(direction);
+            let v_dot_h = self.view.dot(half);
+            if n_dot_l <= 0.0 || v_dot_h <= 0.0 {
+                return None;
+            }
+            // d cancels with the pdf, d * n.h / (4 v.h)
+            let v = v_smith_ggx_correlated(self.roughness, self.n_dot_v, n_dot_l);
+            let f = fresnel(self.f0, direction.dot(half).clamp(0.0, 1.0));
+            let weight = f * (v * 4.0 * v_dot_h * n_dot_l / cos_theta.max(0.0001));
+            Some((direction, weight / specular_chance))
+        } else {
+            // lambert over a cosine weighted pdf is just the albedo
+            let direction = cosine_hemisphere(self.normal, rng);
+            Some((direction, self.diffuse_color / (1.0 - specular_chance)))
+        }
+    }
+}
+
+/// Filtered linear color for pixel x, y of the camera's image
+///
+/// Barycentric and heatmap modes return display colors, the other modes return radiance
+///
+/// Heatmap mode adds the camera rays' work to traversal
+#[cfg(feature = "camera")]
+#[allow(clippy::too_many_arguments)]
+pub fn sample_pixel(
+    camera: &BvhCamera,
+    scene: &RenderScene,
+    tlas: &Tlas,
+    x: u32,
+    y: u32,
+    rng: &mut impl Rng,
+    secondary_rays: &mut usize,
+    traversal: &mut TraversalStats,
+) -> Vec3 {
+    // a lone still sample stays on the pixel center so the image doesn't shimmer
+    let samples = camera.samples.max(1);
+    let jitter = samples > 1 || camera.mode == RenderMode::PathTrace || camera.aperture > 0.0;
+    let mut color = Vec3::ZERO;
+    let mut total_weight = 0.0;
+    for sample in 0..samples {
+        let pixel_offset = if jitter {
+            camera.filter.sample(sample, samples, rng)
+        } else {
+            Vec2::ZERO
+        };
+        let weight = camera.filter.weight(pixel_offset);
+        if weight <= 0.0 {
+            continue;
+        }
+        let u = (x as f32 + 0.5 + pixel_offset.x) / camera.width as f32;
+        let v = (y as f32 + 0.5 + pixel_offset.y) / camera.height as f32;
+
+        let mut ray = if camera.aperture > 0.0 {
+            camera.get_lens_ray(u, v, sample_disk(rng))
+        } else {
+            camera.get_ray(u, v)
+        };
+        let sample_color = match camera.mode {
+            RenderMode::Barycentric => scene
+                .intersect(&mut ray, tlas)
+                .map_or(Vec3::ZERO, |hit| vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v))),
+            RenderMode::Shaded => match scene.intersect(&mut ray, tlas) {
+                Some(hit) => scene.shade(&ray, &hit, tlas, camera, rng, secondary_rays),
+                None => scene.background,
+            },
+            RenderMode::PathTrace => {
+                scene.trace_path(&ray, tlas, camera.max_bounces, rng, secondary_rays)
+            }
+            RenderMode::Heatmap => {
+                let mut stats = TraversalStats::default();
+                scene.intersect_counted(&mut ray, tlas, &mut stats);
+                *traversal += stats;
+                let cost = (stats.nodes() + stats.tri_tests) as f32;
+                let [r, g, b, _] =
+                    heat_color(cost / camera.heatmap_max.max(1) as f32).as_rgba_f32();
+                vec3(r, g, b)
+            }
+        };
+        color += sample_color * weight;
+        total_weight += weight;
+    }
+    if total_weight > 0.0 {
+        color /= total_weight;
+    }
+    color
+}
+
+/// Per tile state render_tiles hands to each pixel
+#[cfg(feature = "camera")]
+pub struct TileRender {
+    pub rng: ChaChaRng,
+    // shadow and occlusion rays traced
+    pub secondary_rays: usize,
+    pub traversal: TraversalStats,
+    // tlas queries made for the tile, with the stats feature, for the caller to add to the tlas
+    pub queries: TraversalStats,
+}
+
+/// Renders the camera's tiles in parallel, shared by BvhCameraPlugin and render_to_buffer
+///
+/// Each tile's rng is seeded from seed and the tile index, so images are repeatable regardless
+/// of thread order. Pixels come back in the order of Tile::pixels for the caller to copy into place
+#[cfg(feature = "camera")]
+pub fn render_tiles<T, F>(
+    camera: &BvhCamera,
+    seed: u64,
+    render_pixel: F,
+) -> Vec<(Tile, Vec<T>, TileRender)>
+where
+    T: Send,
+    F: Fn(u32, u32, &mut TileRender) -> T + Sync,
+{
+    camera
+        .tiles()
+        .enumerate()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(i, tile)| {
+            // seed in the high bits so neighboring seeds don't share tiles
+            let mut state = TileRender {
+                rng: ChaChaRng::seed_from_u64(seed.rotate_left(32) ^ i as u64),
+                secondary_rays: 0,
+                traversal: TraversalStats::default(),
+                queries: TraversalStats::default(),
+            };
+            let render = |state: &mut TileRender| {
+                tile.pixels()
+                    .map(|(x, y)| render_pixel(x, y, state))
+                    .collect::<Vec<_>>()
+            };
+            // summed per tile rather than per query, keeping threads off the shared totals
+            #[cfg(feature = "stats")]
+            let pixels = {
+                let (pixels, queries) = TraversalStats::collect(|| render(&mut state));
+                state.queries = queries;
+                pixels
+            };
+            #[cfg(not(feature = "stats"))]
+            let pixels = render(&mut state);
+            (tile, pixels, state)
+        })
+        .collect()
+}
+
+/// Uniform point in the unit disk
+pub fn sample_disk(rng: &mut impl Rng) -> Vec2 {
+    let r = rng.gen::<f32>().sqrt();
+    let theta = 2.0 * PI * rng.gen::<f32>();
+    Vec2::new(r * theta.cos(), r * theta.sin())
+}
+
+/// Random direction around the normal, weighted by cos theta
+pub fn cosine_hemisphere(normal: Vec3, rng: &mut impl Rng) -> Vec3 {
+    let r1 = rng.gen::<f32>();
+    let r2 = rng.gen::<f32>();
+    let phi = 2.0 * PI * r1;
+    let r = r2.sqrt();
+    let local = vec3(r * phi.cos(), r * phi.sin(), (1.0 - r2).sqrt());
+    let (tangent, bitangent) = normal.any_orthonormal_pair();
+    (tangent * local.x + bitangent * local.y + normal * local.z).normalize()
+}
+
+/// Lambert diffuse plus GGX specular, already multiplied by n dot l
+pub fn brdf(
+    normal: Vec3,
+    view: Vec3,
+    light_dir: Vec3,
+    n_dot_v: f32,
+    roughness: f32,
+    f0: Vec3,
+    diffuse_color: Vec3,
+) -> Vec3 {
+    let n_dot_l = normal.dot(light_dir).clamp(0.0, 1.0);
+    if n_dot_l <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let half = (light_dir + view).normalize();
+    let n_dot_h = normal.dot(half).clamp(0.0, 1.0);
+    let l_dot_h = light_dir.dot(half).clamp(0.0, 1.0);
+
+    let d = d_ggx(roughness, n_dot_h);
+    let v = v_smith_ggx_correlated(roughness, n_dot_v, n_dot_l);
+    let f = fresnel(f0, l_dot_h);
+    let specular = f * (d * v);
+    let diffuse = diffuse_color / PI;
+    (diffuse + specular) * n_dot_l
+}
+
+// Filament's specular terms, https://google.github.io/filament/Filament.html#materialsystem/specularbrdf
+fn d_ggx(roughness: f32, n_dot_h: f32) -> f32 {
+    let one_minus_n_dot_h_squared = 1.0 - n_dot_h * n_dot_h;
+    let a = n_dot_h * roughness;
+    let k = roughness / (one_minus_n_dot_h_squared + a * a);
+    k * k / PI
+}
+
+fn v_smith_ggx_correlated(roughness: f32, n_dot_v: f32, n_dot_l: f32) -> f32 {
+    let a2 = roughness * roughness;
+    let lambda_v = n_dot_l * ((n_dot_v - a2 * n_dot_v) * n_dot_v + a2).sqrt();
+    let lambda_l = n_dot_v * ((n_dot_l - a2 * n_dot_l) * n_dot_l + a2).sqrt();
+    0.5 / (lambda_v + lambda_l)
+}
+
+fn fresnel(f0: Vec3, l_dot_h: f32) -> Vec3 {
+    let f90 = f0.dot(Vec3::splat(50.0 * 0.33)).clamp(0.0, 1.0);
+    f0 + (Vec3::splat(f90) - f0) * (1.0 - l_dot_h).powf(5.0)
+}
+
+// https://www.unrealengine.com/en-US/blog/physically-based-shading-on-mobile
+#[cfg(feature = "camera")]
+fn env_brdf_approx(f0: Vec3, perceptual_roughness: f32, n_dot_v: f32) -> Vec3 {
+    let c0 = Vec4::new(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = Vec4::new(1.0, 0.0425, 1.04, -0.04);
+    let r = perceptual_roughness * c0 + c1;
+    let a004 = (r.x * r.x).min((-9.28 * n_dot_v).exp2()) * r.x + r.y;
+    let ab = Vec2::new(-1.04, 1.04) * a004 + Vec2::new(r.z, r.w);
+    f0 * ab.x + Vec3::splat(ab.y)
+}
+
+// inverse square falloff, smoothed to zero at the light range like bevy
+fn distance_attenuation(distance_squared: f32, range: f32) -> f32 {
+    let factor = distance_squared / (range * range);
+    let smooth_factor = (1.0 - factor * factor).clamp(0.0, 1.0);
+    smooth_factor * smooth_factor / distance_squared.max(0.0001)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Blue to green to red, t from 0 to 1
+pub fn heat_color(t: f32) -> Color {
+    Color::hsl((1.0 - t.clamp(0.0, 1.0)) * 240.0, 1.0, 0.5)
+}
+
+pub fn linear_rgb(color: Color) -> Vec3 {
+    let [r, g, b, _] = color.as_linear_rgba_f32();
+    vec3(r, g, b)
+}
+
+/// Tonemaps linear radiance and encodes it for an Rgba8UnormSrgb image
+pub fn to_srgb_bytes(color: Vec3) -> [u8; 4] {
+    let color = reinhard_luminance(color);
+    let [r, g, b, _] = Color::rgb_linear(color.x, color.y, color.z).as_rgba_f32();
+    [
+        (r.clamp(0.0, 1.0) * 255.0) as u8,
+        (g.clamp(0.0, 1.0) * 255.0) as u8,
+        (b.clamp(0.0, 1.0) * 255.0) as u8,
+        255,
+    ]
+}
+
+// https://64.github.io/tonemapping/, same as bevy
+fn reinhard_luminance(color: Vec3) -> Vec3 {
+    let l_old = color.dot(vec3(0.2126, 0.7152, 0.0722));
+    if l_old <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let l_new = l_old / (1.0 + l_old);
+    color * (l_new / l_old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    #[test]
+    fn samples_cover_the_pixel() {
+        let mut rng = ChaChaRng::seed_from_u64(0);
+        for count in 1..=9 {
+            let mut sum = Vec2::ZERO;
+            let runs = 2000;
+            for _ in 0..runs {
+                for index in 0..count {
+                    let offset = PixelFilter::Box.sample(index, count, &mut rng);
+                    assert!(offset.abs().max_element() <= 0.5);
+                    sum += offset;
+                }
+            }
+            // an uncovered cell would pull the average off center
+            let mean = sum / (runs * count) as f32;
+            assert!(mean.length() < 0.01, "{} samples average {}", count, mean);
+        }
+    }
+
+    #[cfg(feature = "camera")]
+    #[test]
+    fn edges_are_smoothed() {
+        use crate::{
+            bvh::{Bvh, BvhInstance},
+            offline::{render_to_buffer, RenderSettings},
+            parse_mesh,
+        };
+
+        // a white unlit quad whose edge crosses the view at an angle
+        let mut tlas = Tlas::default();
+        let (tris, tri_data) = parse_mesh(&Mesh::from(shape::Quad::new(Vec2::splat(2.0))));
+        let bvh_index = tlas.add_bvh(Bvh::new_with_data(tris, tri_data));
+        let entity = Entity::from_raw(0);
+        let mut instance = BvhInstance::new(entity, bvh_index);
+        let trans = Mat4::from_rotation_z(0.3) * Mat4::from_translation(Vec3::X);
+        instance.update(
+            &GlobalTransform::from_matrix(trans),
+            &tlas.bvhs[bvh_index].nodes[0],
+        );
+        tlas.add_instance(instance);
+        tlas.build();
+
+        let mut settings = RenderSettings::default();
+        settings.scene.materials.insert(
+            entity,
+            SurfaceMaterial {
+                base_color: Vec3::ONE,
+                unlit: true,
+                ..Default::default()
+            },
+        );
+        let render = |samples| {
+            let mut camera = BvhCamera::new(32, 32);
+            camera.mode = RenderMode::Shaded;
+            camera.samples = samples;
+            camera.update(&GlobalTransform::from_xyz(0.0, 0.0, 3.0));
+            render_to_buffer(&tlas, &camera, &settings).pixels
+        };
+        let partial = |pixels: &[Vec3]| {
+            pixels
+                .iter()
+                .filter(|color| color.x > 0.05 && color.x < 0.95)
+                .count()
+        };
+
+        let single = render(1);
+        assert!(single.iter().any(|color| color.x == 1.0));
+        assert_eq!(partial(&single), 0);
+        let smoothed = render(8);
+        assert!(partial(&smoothed) > 10);
+        assert_eq!(render(8), smoothed);
+    }
+}
@@ -1,7 +1,10 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
+use std::{cmp::Ordering, collections::BinaryHeap};
 
 
-use crate::{ Bvh, BvhInstance, Aabb};
+use crate::{ Bvh, BvhInstance, Aabb, bvh::TriPair, filter::{BvhLayers, QueryFilter}, ray::Hit};
+#[cfg(feature = "stats")]
+use crate::ray::{QueryTotals, TraversalStats};
 
 #[derive(Default, Debug, Copy, Clone)]
 pub struct TlasNode {
@@ -21,6 +24,11 @@ pub struct Tlas {
     pub tlas_nodes: Vec<TlasNode>,    
     pub blas: Vec<BvhInstance>,
     pub bvhs: Vec<Bvh>,
+    // entity to blas index
+    instance_lookup: HashMap<Entity, usize>,
+    // kept per tlas so apps in the same process don't mix their stats
+    #[cfg(feature = "stats")]
+    query_stats: QueryTotals,
 }
 
 impl Default for Tlas {
@@ -29,6 +37,9 @@ impl Default for Tlas {
             tlas_nodes: Vec::with_capacity(0),
             blas: Default::default(),
             bvhs: Default::default(),
+            instance_lookup: Default::default(),
+            #[cfg(feature = "stats")]
+            query_stats: Default::default(),
         }
     }
 }
@@ -40,9 +51,157 @@ impl Tlas {
     }
 
     pub fn add_instance(&mut self, instnace: BvhInstance) {
+        self.instance_lookup
+            .insert(instnace.entity, self.blas.len());
         self.blas.push(instnace);
     }
 
+    pub fn get_instance(&self, entity: Entity) -> Option<&BvhInstance> {
+        self.instance_lookup
+            .get(&entity)
+            .map(|index| &self.blas[*index])
+    }
+
+    /// Adds queries gathered with TraversalStats::collect to the totals
+    #[cfg(feature = "stats")]
+    pub fn add_query_stats(&self, stats: TraversalStats) {
+        self.query_stats.add(stats);
+    }
+
+    /// Every query against this tlas since the last call, BvhPlugin moves these into BvhStats
+    /// each frame
+    #[cfg(feature = "stats")]
+    pub fn take_query_stats(&self) -> TraversalStats {
+        self.query_stats.take()
+    }
+
+    /// World space normal at a hit, flipped to face `towards`
+    ///
+    /// Interpolates vertex normals when the bvh has them, otherwise uses the face normal
+    pub fn hit_normal(&self, hit: &Hit, towards: Vec3) -> Vec3 {
+        let instance = self
+            .get_instance(hit.entity)
+            .expect("Hit entity not in tlas");
+        let bvh = &self.bvhs[instance.bvh_index];
+        let local_normal = match bvh.tri_data.get(hit.tri_index) {
+            Some(data) => data.normal(hit.u, hit.v),
+            None => {
+                let tri = &bvh.tris[hit.tri_index];
+                (tri.vertex1 - tri.vertex0).cross(tri.vertex2 - tri.vertex0)
+            }
+        };
+        let normal = instance
+            .inv_trans
+            .transpose()
+            .transform_vector3(local_normal)
+            .normalize();
+        if normal.dot(towards) < 0.0 {
+            -normal
+        } else {
+            normal
+        }
+    }
+
+    /// Interpolated texture coordinates at a hit, zero if the mesh had no uvs
+    pub fn hit_uv(&self, hit: &Hit) -> Vec2 {
+        self.get_instance(hit.entity)
+            .and_then(|instance| self.bvhs[instance.bvh_index].tri_data.get(hit.tri_index))
+            .map_or(Vec2::ZERO, |data| data.uv(hit.u, hit.v))
+    }
+
+    /// Tests if a world space point is inside the entity's mesh, None if the entity has no bvh
+    pub fn contains_point(&self, entity: Entity, point: Vec3) -> Option<bool> {
+        let instance = self.get_instance(entity)?;
+        let local_point = instance.inv_trans.transform_point3(point);
+        Some(self.bvhs[instance.bvh_index].contains_point(local_point))
+    }
+
+    /// Overlapping triangles between two entities meshes, None if either has no bvh
+    pub fn overlapping_tris(&self, a: Entity, b: Entity) -> Option<Vec<TriPair>> {
+        let a = self.get_instance(a)?;
+        let b = self.get_instance(b)?;
+        Some(a.overlapping_tris(b, &self.bvhs))
+    }
+
+    /// Finds the k closest instances to a point by their world bounds, sorted nearest first
+    ///
+    /// Distance is zero when the point is inside an instance's bounds
+    pub fn nearest_instances(
+        &self,
+        point: Vec3,
+        k: usize,
+        filter: &QueryFilter,
+    ) -> Vec<(Entity, f32)> {
+        self.find_nearest(point, k, filter, false)
+    }
+
+    /// Same as nearest_instances, but measured to the closest point on each mesh surface
+    pub fn nearest_instances_exact(
+        &self,
+        point: Vec3,
+        k: usize,
+        filter: &QueryFilter,
+    ) -> Vec<(Entity, f32)> {
+        self.find_nearest(point, k, filter, true)
+    }
+
+    // Best first search over the tlas nodes, a node's bounds distance is never more
+    // than any distance found under it, so we can stop once we have k closer results
+    fn find_nearest(
+        &self,
+        point: Vec3,
+        k: usize,
+        filter: &QueryFilter,
+        exact: bool,
+    ) -> Vec<(Entity, f32)> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("find_nearest").entered();
+        let mut results: Vec<(Entity, f32)> = Vec::with_capacity(k + 1);
+        if k == 0 || self.blas.is_empty() || self.tlas_nodes.is_empty() {
+            return results;
+        }
+
+        let mut heap = BinaryHeap::new();
+        heap.push(NearestCandidate {
+            distance: self.tlas_nodes[0].aabb.distance_squared(point).sqrt(),
+            node: 0,
+        });
+        while let Some(NearestCandidate { distance, node }) = heap.pop() {
+            if results.len() == k && distance >= results[k - 1].1 {
+                break;
+            }
+            let node = &self.tlas_nodes[node];
+            if node.is_leaf() {
+                let instance = &self.blas[node.blas as usize];
+                if !filter.accepts(instance) {
+                    continue;
+                }
+                let distance = if exact {
+                    match instance.closest_point(point, &self.bvhs) {
+                        Some((closest, _)) => closest.distance(point),
+                        None => continue,
+                    }
+                } else {
+                    distance
+                };
+                let index = results.partition_point(|(_, d)| *d <= distance);
+                results.insert(index, (instance.entity, distance));
+                results.truncate(k);
+                continue;
+            }
+            for child in [node.left_right & 0xffff, node.left_right >> 16] {
+                heap.push(NearestCandidate {
+                    distance: self.tlas_nodes[child as usize]
+                        .aabb
+                        .distance_squared(point)
+                        .sqrt(),
+                    node: child as usize,
+                });
+            }
+        }
+        results
+    }
+
     pub fn build(&mut self) {
         self.tlas_nodes = Vec::with_capacity(self.blas.len() + 1);
         // reserve root node
@@ -114,12 +273,172 @@ impl Tlas {
         best_b
     }
 
-    pub fn update_bvh_instances(&mut self, query: &Query<&GlobalTransform>) {
+    pub fn update_bvh_instances(&mut self, query: &Query<(&GlobalTransform, Option<&BvhLayers>)>) {
         for instance in &mut self.blas {
             let bvh = &self.bvhs[instance.bvh_index];
-            if let Ok(trans) = query.get(instance.entity) {
+            if let Ok((trans, layers)) = query.get(instance.entity) {
                 instance.update(trans, &bvh.nodes[0]);
+                instance.layers = layers.copied().unwrap_or_default().0;
             }
         }
     }
 }
+
+// Ordered so the BinaryHeap pops the closest node first
+struct NearestCandidate {
+    distance: f32,
+    node: usize,
+}
+
+impl PartialEq for NearestCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance == other.distance
+    }
+}
+
+impl Eq for NearestCandidate {}
+
+impl PartialOrd for NearestCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NearestCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_mesh;
+
+    // one instance of the mesh per transform, entity ids in order
+    fn build_tlas(mesh: Mesh, transforms: &[Transform]) -> Tlas {
+        let (tris, tri_data) = parse_mesh(&mesh);
+        let mut tlas = Tlas::default();
+        let bvh_index = tlas.add_bvh(Bvh::new_with_data(tris, tri_data));
+        for (i, transform) in transforms.iter().enumerate() {
+            let mut instance = BvhInstance::new(Entity::from_raw(i as u32), bvh_index);
+            instance.update(
+                &GlobalTransform::from(*transform),
+                &tlas.bvhs[bvh_index].nodes[0],
+            );
+            tlas.add_instance(instance);
+        }
+        tlas.build();
+        tlas
+    }
+
+    #[test]
+    fn transformed_instance_contains_point() {
+        let transform = Transform::from_xyz(5.0, 0.0, 0.0)
+            .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4))
+            .with_scale(Vec3::splat(2.0));
+        let tlas = build_tlas(Mesh::from(shape::Cube { size: 2.0 }), &[transform]);
+        let entity = Entity::from_raw(0);
+        let world = |local: Vec3| transform.compute_matrix().transform_point3(local);
+
+        assert_eq!(tlas.contains_point(entity, world(Vec3::ZERO)), Some(true));
+        assert_eq!(
+            tlas.contains_point(entity, world(Vec3::new(0.999, 0.5, 0.0))),
+            Some(true)
+        );
+        assert_eq!(
+            tlas.contains_point(entity, world(Vec3::new(1.001, 0.5, 0.0))),
+            Some(false)
+        );
+        assert_eq!(tlas.contains_point(entity, world(Vec3::ONE)), Some(true));
+        // inside the world bounds, but outside the rotated cube
+        assert_eq!(
+            tlas.contains_point(entity, Vec3::new(7.0, 0.0, 1.9)),
+            Some(false)
+        );
+        assert_eq!(tlas.contains_point(entity, Vec3::ZERO), Some(false));
+        assert_eq!(tlas.contains_point(Entity::from_raw(1), Vec3::ZERO), None);
+    }
+
+    // a cube turned 45 degrees, so its bounds reach much closer than its faces,
+    // then plain cubes 2.7, 5 and 9 away
+    fn nearest_tlas() -> Tlas {
+        let turned = Transform::from_xyz(3.0, 0.0, 3.0)
+            .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4));
+        build_tlas(
+            Mesh::from(shape::Cube { size: 2.0 }),
+            &[
+                turned,
+                Transform::from_xyz(0.0, 0.0, -3.7),
+                Transform::from_xyz(-6.0, 0.0, 0.0),
+                Transform::from_xyz(0.0, 10.0, 0.0),
+            ],
+        )
+    }
+
+    fn ids(results: &[(Entity, f32)]) -> Vec<u32> {
+        results.iter().map(|(entity, _)| entity.id()).collect()
+    }
+
+    #[test]
+    fn nearest_instances_sorted_and_truncated() {
+        let tlas = nearest_tlas();
+        let all = tlas.nearest_instances(Vec3::ZERO, 10, &QueryFilter::default());
+        assert_eq!(ids(&all), [0, 1, 2, 3]);
+        let turned_bounds = (Vec2::splat(3.0 - std::f32::consts::SQRT_2)).length();
+        assert!((all[0].1 - turned_bounds).abs() < 0.001);
+        for (result, expected) in all[1..].iter().zip([2.7, 5.0, 9.0]) {
+            assert!((result.1 - expected).abs() < 0.001);
+        }
+
+        let two = tlas.nearest_instances(Vec3::ZERO, 2, &QueryFilter::default());
+        assert_eq!(two, all[..2]);
+        assert!(tlas
+            .nearest_instances(Vec3::ZERO, 0, &QueryFilter::default())
+            .is_empty());
+
+        // inside the bounds
+        let inside = tlas.nearest_instances(Vec3::new(-6.5, 0.0, 0.0), 1, &QueryFilter::default());
+        assert_eq!(inside, [(Entity::from_raw(2), 0.0)]);
+    }
+
+    #[test]
+    fn nearest_instances_filtered() {
+        let mut tlas = nearest_tlas();
+        let skip_first = |entity: Entity| entity.id() != 0;
+        let filter = QueryFilter::default().with_predicate(&skip_first);
+        assert_eq!(ids(&tlas.nearest_instances(Vec3::ZERO, 2, &filter)), [1, 2]);
+        assert_eq!(
+            ids(&tlas.nearest_instances_exact(Vec3::ZERO, 2, &filter)),
+            [1, 2]
+        );
+
+        for instance in &mut tlas.blas {
+            instance.layers = BvhLayers::layer(0).0;
+        }
+        tlas.blas[1].layers = BvhLayers::layer(1).0;
+        let filter = QueryFilter::new(BvhLayers::layer(1).0);
+        assert_eq!(ids(&tlas.nearest_instances(Vec3::ZERO, 10, &filter)), [1]);
+        let filter = QueryFilter::new(BvhLayers::layer(0).0);
+        assert_eq!(
+            ids(&tlas.nearest_instances(Vec3::ZERO, 10, &filter)),
+            [0, 2, 3]
+        );
+    }
+
+    #[test]
+    fn nearest_instances_exact_uses_the_surface() {
+        let tlas = nearest_tlas();
+        let bounds = tlas.nearest_instances(Vec3::ZERO, 2, &QueryFilter::default());
+        assert_eq!(ids(&bounds), [0, 1]);
+
+        // the turned cube's face is 1 from its center, but its bounds corner is closer than 2.7
+        let exact = tlas.nearest_instances_exact(Vec3::ZERO, 2, &QueryFilter::default());
+        assert_eq!(ids(&exact), [1, 0]);
+        assert!((exact[0].1 - 2.7).abs() < 0.001);
+        assert!((exact[1].1 - (18.0f32.sqrt() - 1.0)).abs() < 0.001);
+
+        let one = tlas.nearest_instances_exact(Vec3::ZERO, 1, &QueryFilter::default());
+        assert_eq!(one, exact[..1]);
+    }
+}
@@ -1,8 +1,10 @@
 use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
 
 // TODO: Will be replaced by bevy mesh data
 //, stop gap to get things working
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
 pub struct Tri {
     pub vertex0: Vec3,
     pub vertex1: Vec3,
@@ -10,6 +12,29 @@ pub struct Tri {
     pub centroid: Vec3,
 }
 
+/// Per vertex data not needed for traversal, only used when shading hits
+#[derive(Default, Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct TriData {
+    pub normal0: Vec3,
+    pub normal1: Vec3,
+    pub normal2: Vec3,
+    pub uv0: Vec2,
+    pub uv1: Vec2,
+    pub uv2: Vec2,
+}
+
+impl TriData {
+    // interpolate using the hit barycentrics, not normalized
+    pub fn normal(&self, u: f32, v: f32) -> Vec3 {
+        self.normal0 * (1.0 - u - v) + self.normal1 * u + self.normal2 * v
+    }
+
+    pub fn uv(&self, u: f32, v: f32) -> Vec2 {
+        self.uv0 * (1.0 - u - v) + self.uv1 * u + self.uv2 * v
+    }
+}
+
 impl Tri {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Self {
         Tri {
@@ -19,4 +44,107 @@ impl Tri {
             centroid: (v0 + v1 + v2) / 3.0,
         }
     }
+
+    pub fn transformed(&self, trans: &Mat4) -> Tri {
+        Tri::new(
+            trans.transform_point3(self.vertex0),
+            trans.transform_point3(self.vertex1),
+            trans.transform_point3(self.vertex2),
+        )
+    }
+
+    // Real-Time Collision Detection, Christer Ericson, 5.1.5
+    pub fn closest_point(&self, p: Vec3) -> Vec3 {
+        let a = self.vertex0;
+        let b = self.vertex1;
+        let c = self.vertex2;
+        let ab = b - a;
+        let ac = c - a;
+
+        // vertex region outside a
+        let ap = p - a;
+        let d1 = ab.dot(ap);
+        let d2 = ac.dot(ap);
+        if d1 <= 0.0 && d2 <= 0.0 {
+            return a;
+        }
+
+        // vertex region outside b
+        let bp = p - b;
+        let d3 = ab.dot(bp);
+        let d4 = ac.dot(bp);
+        if d3 >= 0.0 && d4 <= d3 {
+            return b;
+        }
+
+        // edge region of ab
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            return a + ab * (d1 / (d1 - d3));
+        }
+
+        // vertex region outside c
+        let cp = p - c;
+        let d5 = ab.dot(cp);
+        let d6 = ac.dot(cp);
+        if d6 >= 0.0 && d5 <= d6 {
+            return c;
+        }
+
+        // edge region of ac
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            return a + ac * (d2 / (d2 - d6));
+        }
+
+        // edge region of bc
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+            return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+        }
+
+        // inside face region
+        let denom = 1.0 / (va + vb + vc);
+        a + ab * (vb * denom) + ac * (vc * denom)
+    }
+
+    /// Triangle vs triangle using the separating axis theorem, touching triangles don't count
+    ///
+    /// Coplanar triangles count as touching, so they never intersect
+    pub fn intersects_tri(&self, other: &Tri) -> bool {
+        let a = [self.vertex0, self.vertex1, self.vertex2];
+        let b = [other.vertex0, other.vertex1, other.vertex2];
+        let edges_a = [a[1] - a[0], a[2] - a[1], a[0] - a[2]];
+        let edges_b = [b[1] - b[0], b[2] - b[1], b[0] - b[2]];
+        let normal_a = edges_a[0].cross(edges_a[1]);
+        let normal_b = edges_b[0].cross(edges_b[1]);
+
+        // both normals and the edge cross products, coplanar triangles are already
+        // separated by the first normal so there's no need for in plane axes
+        let mut axes = [Vec3::ZERO; 11];
+        axes[0] = normal_a;
+        axes[1] = normal_b;
+        for i in 0..3 {
+            for j in 0..3 {
+                axes[2 + i * 3 + j] = edges_a[i].cross(edges_b[j]);
+            }
+        }
+
+        !axes.iter().any(|axis| {
+            // parallel edges give a zero axis, nothing to test
+            if axis.length_squared() < 1e-12 {
+                return false;
+            }
+            let (min_a, max_a) = project(&a, *axis);
+            let (min_b, max_b) = project(&b, *axis);
+            max_a <= min_b || max_b <= min_a
+        })
+    }
+}
+
+fn project(points: &[Vec3; 3], axis: Vec3) -> (f32, f32) {
+    let d0 = points[0].dot(axis);
+    let d1 = points[1].dot(axis);
+    let d2 = points[2].dot(axis);
+    (d0.min(d1).min(d2), d0.max(d1).max(d2))
 }